@@ -1,8 +1,9 @@
 use bitflags::bitflags;
 
-use self::syscall::REG_REPRESENTATIONS;
+use self::syscall::{REG_REPRESENTATIONS, XMM_REPRESENTATIONS};
 
 pub mod syscall;
+pub mod syscall_table;
 
 type InternalRegister = u8;
 
@@ -63,6 +64,35 @@ impl PartialEq<RegAllocation> for Register {
     }
 }
 
+#[repr(transparent)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct XmmAllocation(InternalRegister);
+#[repr(transparent)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct XmmRegister(pub InternalRegister);
+
+impl XmmAllocation {
+    pub const fn reg(&self) -> XmmRegister {
+        XmmRegister(self.0)
+    }
+}
+
+#[repr(transparent)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct XmmRequest(pub InternalRegister);
+
+impl PartialEq<XmmRequest> for XmmAllocation {
+    fn eq(&self, other: &XmmRequest) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialEq<XmmAllocation> for XmmRequest {
+    fn eq(&self, other: &XmmAllocation) -> bool {
+        self.0 == other.0
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     struct RegisterAllocatorInternal: u16 {
@@ -85,16 +115,52 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct XmmAllocatorInternal: u16 {
+        const xmm0  = 1 << 0;
+        const xmm1  = 1 << 1;
+        const xmm2  = 1 << 2;
+        const xmm3  = 1 << 3;
+        const xmm4  = 1 << 4;
+        const xmm5  = 1 << 5;
+        const xmm6  = 1 << 6;
+        const xmm7  = 1 << 7;
+        const xmm8  = 1 << 8;
+        const xmm9  = 1 << 9;
+        const xmm10 = 1 << 10;
+        const xmm11 = 1 << 11;
+        const xmm12 = 1 << 12;
+        const xmm13 = 1 << 13;
+        const xmm14 = 1 << 14;
+        const xmm15 = 1 << 15;
+    }
+}
+
+/// Registers currently holding live values, as returned by [`RegisterAllocator::suspend`]
+#[derive(Debug)]
+pub struct SuspendedRegisters {
+    pub gp: Vec<Register>,
+    pub xmm: Vec<XmmRegister>,
+}
+
 pub struct RegisterAllocator {
     available: RegisterAllocatorInternal,
+    available_xmm: XmmAllocatorInternal,
 }
 impl RegisterAllocator {
     pub fn new() -> Self {
         Self {
             available: !(RegisterAllocatorInternal::rsp | RegisterAllocatorInternal::rbp),
+            available_xmm: XmmAllocatorInternal::all(),
         }
     }
 
+    pub fn is_available(&self, req: RegisterRequest) -> bool {
+        self.available
+            .contains(RegisterAllocatorInternal::from_bits_truncate(1 << req.0))
+    }
+
     pub fn free(&mut self, reg: RegAllocation) {
         self.available ^= RegisterAllocatorInternal::from_bits_truncate(1 << reg.0);
     }
@@ -126,6 +192,70 @@ impl RegisterAllocator {
             req.0, REG_REPRESENTATIONS[req.0 as usize]
         )
     }
+
+    pub fn is_available_xmm(&self, req: XmmRequest) -> bool {
+        self.available_xmm
+            .contains(XmmAllocatorInternal::from_bits_truncate(1 << req.0))
+    }
+
+    pub fn free_xmm(&mut self, reg: XmmAllocation) {
+        self.available_xmm ^= XmmAllocatorInternal::from_bits_truncate(1 << reg.0);
+    }
+
+    pub fn allocate_any_xmm(&mut self) -> XmmAllocation {
+        for i in 0..16u8 {
+            let mask = XmmAllocatorInternal::from_bits_truncate(1 << i);
+            if !((self.available_xmm & mask).is_empty()) {
+                self.available_xmm ^= mask;
+                return XmmAllocation(i);
+            }
+        }
+        panic!("No xmm registers available")
+    }
+
+    pub fn allocate_xmm(&mut self, req: XmmRequest) -> XmmAllocation {
+        let mask = XmmAllocatorInternal::from_bits_truncate(1 << req.0);
+        if !((self.available_xmm & mask).is_empty()) {
+            self.available_xmm ^= mask;
+            return XmmAllocation(req.0);
+        }
+        panic!(
+            "Register {} ({}) isn't available",
+            req.0, XMM_REPRESENTATIONS[req.0 as usize]
+        )
+    }
+
+    /// Marks every register in use as available, returning the ones that were in use so that
+    /// the caller can save them (e.g. around a call) and later [`Self::resume`] them
+    pub fn suspend(&mut self) -> SuspendedRegisters {
+        let fixed = RegisterAllocatorInternal::rsp | RegisterAllocatorInternal::rbp;
+        let in_use = !(self.available | fixed);
+        let gp = (0..16u8)
+            .filter(|i| in_use.contains(RegisterAllocatorInternal::from_bits_truncate(1 << i)))
+            .map(Register)
+            .collect();
+        let xmm = (0..16u8)
+            .filter(|i| {
+                !self
+                    .available_xmm
+                    .contains(XmmAllocatorInternal::from_bits_truncate(1 << i))
+            })
+            .map(XmmRegister)
+            .collect();
+        self.available |= in_use;
+        self.available_xmm = XmmAllocatorInternal::all();
+        SuspendedRegisters { gp, xmm }
+    }
+
+    /// Marks the registers returned by [`Self::suspend`] as in use again
+    pub fn resume(&mut self, suspended: &SuspendedRegisters) {
+        for reg in &suspended.gp {
+            self.allocate(RegisterRequest(reg.0));
+        }
+        for reg in &suspended.xmm {
+            self.allocate_xmm(XmmRequest(reg.0));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -134,14 +264,229 @@ pub enum Constant<Lbl> {
     Tbd(Lbl),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatSize {
+    Single,
+    Double,
+}
+
+/// Size of the low part of a general purpose register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntSize {
+    Byte,
+    Word,
+    Dword,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// x86 condition codes, as encoded in the low nibble of `setcc`/`jcc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Condition {
+    Overflow = 0x0,
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    BelowOrEqual = 0x6,
+    Above = 0x7,
+    Parity = 0xA,
+    NotParity = 0xB,
+    Less = 0xC,
+    GreaterOrEqual = 0xD,
+    LessOrEqual = 0xE,
+    Greater = 0xF,
+}
+
 #[derive(Debug)]
 pub enum Instr<Lbl> {
     SetConstant(Register, Constant<Lbl>),
-    MoveRegs { dest: Register, orig: Register },
+    MoveRegs {
+        dest: Register,
+        orig: Register,
+    },
+    /// `dest = [base + offset]`
+    Load {
+        dest: Register,
+        base: Register,
+        offset: i32,
+    },
+    /// `[base + offset] = orig`
+    Store {
+        base: Register,
+        offset: i32,
+        orig: Register,
+    },
+    /// Atomically swaps `reg` with `[base + offset]`
+    Exchange {
+        base: Register,
+        offset: i32,
+        reg: Register,
+    },
+    /// Atomically adds `reg` to `[base + offset]`, setting `reg` to the previous value
+    AtomicAdd {
+        base: Register,
+        offset: i32,
+        reg: Register,
+    },
+    /// Sign or zero extending `dest = [base + offset]` of `size`
+    LoadNarrow {
+        size: IntSize,
+        signed: bool,
+        dest: Register,
+        base: Register,
+        offset: i32,
+    },
+    /// `[base + offset] = orig` of the low `size` part of `orig`
+    StoreNarrow {
+        size: IntSize,
+        base: Register,
+        offset: i32,
+        orig: Register,
+    },
+    /// `dest = base + offset` (`lea`)
+    LoadAddress {
+        dest: Register,
+        base: Register,
+        offset: i32,
+    },
+    AddConstant {
+        dest: Register,
+        val: i32,
+    },
+    SubConstant {
+        dest: Register,
+        val: i32,
+    },
+    /// `sub rsp, <value of the label>`, for frames whose size is only known at the end of the
+    /// function
+    ReserveFrame(Lbl),
+    /// `dest = dest op orig`
+    IntOp {
+        op: IntOp,
+        dest: Register,
+        orig: Register,
+    },
+    /// Sign extends rax into rdx (`cqo`)
+    SignExtendRax,
+    /// Signed division of rdx:rax, leaving the quotient in rax and the remainder in rdx
+    SignedDiv(Register),
+    /// Unsigned division of rdx:rax, leaving the quotient in rax and the remainder in rdx
+    UnsignedDiv(Register),
+    /// Unsigned multiplication of rax into rdx:rax, setting the carry flag if rdx isn't 0
+    UnsignedMul(Register),
+    /// Sign extends the low `size` part of `orig` into `dest`
+    SignExtend {
+        size: IntSize,
+        dest: Register,
+        orig: Register,
+    },
+    /// Zero extends the low `size` part of `orig` into `dest`
+    ZeroExtend {
+        size: IntSize,
+        dest: Register,
+        orig: Register,
+    },
+    /// Sets the flags according to `lhs - rhs`
+    Compare {
+        lhs: Register,
+        rhs: Register,
+    },
+    CompareConstant {
+        lhs: Register,
+        val: i32,
+    },
+    /// Sets the flags according to `lhs - [base + offset]`
+    CompareMem {
+        lhs: Register,
+        base: Register,
+        offset: i32,
+    },
+    /// `dest = condition ? 1 : 0`
+    SetCondition(Condition, Register),
+    And {
+        dest: Register,
+        orig: Register,
+    },
+    Or {
+        dest: Register,
+        orig: Register,
+    },
+    /// Logical shift of `dest` right by `amount` bits
+    ShiftRight {
+        dest: Register,
+        amount: u8,
+    },
+    MoveXmm {
+        dest: XmmRegister,
+        orig: XmmRegister,
+    },
+    LoadXmm {
+        size: FloatSize,
+        dest: XmmRegister,
+        base: Register,
+        offset: i32,
+    },
+    StoreXmm {
+        size: FloatSize,
+        base: Register,
+        offset: i32,
+        orig: XmmRegister,
+    },
+    /// `dest = dest op orig`
+    FloatOp {
+        op: FloatOp,
+        size: FloatSize,
+        dest: XmmRegister,
+        orig: XmmRegister,
+    },
+    /// Sets the flags as an unsigned comparison of `lhs` with `rhs`, with unordered operands
+    /// setting ZF, PF and CF
+    FloatCompare {
+        size: FloatSize,
+        lhs: XmmRegister,
+        rhs: XmmRegister,
+    },
+    IntToFloat {
+        size: FloatSize,
+        dest: XmmRegister,
+        orig: Register,
+    },
+    /// Truncating conversion
+    FloatToInt {
+        size: FloatSize,
+        dest: Register,
+        orig: XmmRegister,
+    },
+    /// Converts `orig` (of the opposite size) into a float of size `to`
+    FloatResize {
+        to: FloatSize,
+        dest: XmmRegister,
+        orig: XmmRegister,
+    },
     Push(Register),
     Pop(Register),
     FreeRegister(Register),
+    FreeXmmRegister,
     Syscall,
     Ret,
-    Call(Lbl)
+    Call(Lbl),
+    CallIndirect(Register),
+    Jump(Lbl),
+    JumpIndirect(Register),
+    JumpIf(Condition, Lbl),
 }
@@ -1,4 +1,4 @@
-use super::{RegisterRequest, Register};
+use super::{Register, RegisterRequest, XmmRequest};
 
 macro_rules! reg_list {
     [$($reg:expr),*] => {
@@ -6,10 +6,16 @@ macro_rules! reg_list {
     };
 }
 
+macro_rules! xmm_list {
+    [$($reg:expr),*] => {
+        [$(XmmRequest($reg)),*]
+    };
+}
+
 /// REGISTER NUMS:    0    1    2    3    4    5    6    7    8    9    10    11    12    13    14    15
 /// 8 BYTE Registers: rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8,  r9,  r10,  r11,  r12,  r13,  r14,  r15
 pub const ARG_REGISTERS: [RegisterRequest; 6] = reg_list![7, 6, 2, 10, 8, 9];
-pub const RETURN_REGISTERS: [RegisterRequest; 2] = reg_list![0, 1];
+pub const RETURN_REGISTERS: [RegisterRequest; 2] = reg_list![0, 2];
 pub const SYSCALL_REG: RegisterRequest = RegisterRequest(0);
 pub const REG_REPRESENTATIONS: [&str; 16] = [
     "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
@@ -19,3 +25,15 @@ pub const REG_REPRESENTATIONS: [&str; 16] = [
 pub const RETURN_REG: RegisterRequest = RegisterRequest(0);
 pub const STACK_FRAME_POINTER: Register = Register(5);
 pub const STACK_POINTER: Register = Register(4);
+
+/// SysV function calls differ from syscalls in using rcx instead of r10 for the 4th argument
+pub const CALL_ARG_REGISTERS: [RegisterRequest; 6] = reg_list![7, 6, 2, 1, 8, 9];
+pub const FLOAT_ARG_REGISTERS: [XmmRequest; 8] = xmm_list![0, 1, 2, 3, 4, 5, 6, 7];
+pub const FLOAT_RETURN_REG: XmmRequest = XmmRequest(0);
+pub const FLOAT_RETURN_REGISTERS: [XmmRequest; 2] = xmm_list![0, 1];
+/// Caller saved and not used for arguments, so it can hold the target of an indirect call
+pub const INDIRECT_CALL_REG: RegisterRequest = RegisterRequest(11);
+pub const XMM_REPRESENTATIONS: [&str; 16] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10",
+    "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
@@ -0,0 +1,329 @@
+use crate::data::Type;
+
+use self::ArgKind::{Int, Ptr};
+
+/// What a syscall expects in an argument register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// Integers, flags & file descriptors
+    Int,
+    /// Addresses, also accepted from 64 bit integers
+    Ptr,
+}
+
+impl ArgKind {
+    pub fn accepts(self, ty: &Type) -> bool {
+        match self {
+            Self::Int => ty.int_repr().is_some() && *ty != Type::Ptr,
+            Self::Ptr => matches!(ty, Type::Ptr | Type::I64 | Type::U64),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SyscallSignature {
+    pub name: &'static str,
+    pub nr: u64,
+    pub args: &'static [ArgKind],
+}
+
+macro_rules! syscalls {
+    [$($name:ident = $nr:literal ($($arg:ident),*)),* $(,)?] => {
+        &[$(SyscallSignature {
+            name: stringify!($name),
+            nr: $nr,
+            args: &[$($arg),*],
+        }),*]
+    };
+}
+
+/// Linux x86-64 syscalls, from `arch/x86/entry/syscalls/syscall_64.tbl`
+pub const SYSCALLS: &[SyscallSignature] = syscalls![
+    read = 0(Int, Ptr, Int),
+    write = 1(Int, Ptr, Int),
+    open = 2(Ptr, Int, Int),
+    close = 3(Int),
+    stat = 4(Ptr, Ptr),
+    fstat = 5(Int, Ptr),
+    lstat = 6(Ptr, Ptr),
+    poll = 7(Ptr, Int, Int),
+    lseek = 8(Int, Int, Int),
+    mmap = 9(Ptr, Int, Int, Int, Int, Int),
+    mprotect = 10(Ptr, Int, Int),
+    munmap = 11(Ptr, Int),
+    brk = 12(Ptr),
+    rt_sigaction = 13(Int, Ptr, Ptr, Int),
+    rt_sigprocmask = 14(Int, Ptr, Ptr, Int),
+    rt_sigreturn = 15(),
+    ioctl = 16(Int, Int, Int),
+    pread64 = 17(Int, Ptr, Int, Int),
+    pwrite64 = 18(Int, Ptr, Int, Int),
+    readv = 19(Int, Ptr, Int),
+    writev = 20(Int, Ptr, Int),
+    access = 21(Ptr, Int),
+    pipe = 22(Ptr),
+    select = 23(Int, Ptr, Ptr, Ptr, Ptr),
+    sched_yield = 24(),
+    mremap = 25(Ptr, Int, Int, Int, Ptr),
+    msync = 26(Ptr, Int, Int),
+    mincore = 27(Ptr, Int, Ptr),
+    madvise = 28(Ptr, Int, Int),
+    dup = 32(Int),
+    dup2 = 33(Int, Int),
+    pause = 34(),
+    nanosleep = 35(Ptr, Ptr),
+    getitimer = 36(Int, Ptr),
+    alarm = 37(Int),
+    setitimer = 38(Int, Ptr, Ptr),
+    getpid = 39(),
+    sendfile = 40(Int, Int, Ptr, Int),
+    socket = 41(Int, Int, Int),
+    connect = 42(Int, Ptr, Int),
+    accept = 43(Int, Ptr, Ptr),
+    sendto = 44(Int, Ptr, Int, Int, Ptr, Int),
+    recvfrom = 45(Int, Ptr, Int, Int, Ptr, Ptr),
+    sendmsg = 46(Int, Ptr, Int),
+    recvmsg = 47(Int, Ptr, Int),
+    shutdown = 48(Int, Int),
+    bind = 49(Int, Ptr, Int),
+    listen = 50(Int, Int),
+    getsockname = 51(Int, Ptr, Ptr),
+    getpeername = 52(Int, Ptr, Ptr),
+    socketpair = 53(Int, Int, Int, Ptr),
+    setsockopt = 54(Int, Int, Int, Ptr, Int),
+    getsockopt = 55(Int, Int, Int, Ptr, Ptr),
+    clone = 56(Int, Ptr, Ptr, Ptr, Int),
+    fork = 57(),
+    vfork = 58(),
+    execve = 59(Ptr, Ptr, Ptr),
+    exit = 60(Int),
+    wait4 = 61(Int, Ptr, Int, Ptr),
+    kill = 62(Int, Int),
+    uname = 63(Ptr),
+    fcntl = 72(Int, Int, Int),
+    flock = 73(Int, Int),
+    fsync = 74(Int),
+    fdatasync = 75(Int),
+    truncate = 76(Ptr, Int),
+    ftruncate = 77(Int, Int),
+    getdents = 78(Int, Ptr, Int),
+    getcwd = 79(Ptr, Int),
+    chdir = 80(Ptr),
+    fchdir = 81(Int),
+    rename = 82(Ptr, Ptr),
+    mkdir = 83(Ptr, Int),
+    rmdir = 84(Ptr),
+    creat = 85(Ptr, Int),
+    link = 86(Ptr, Ptr),
+    unlink = 87(Ptr),
+    symlink = 88(Ptr, Ptr),
+    readlink = 89(Ptr, Ptr, Int),
+    chmod = 90(Ptr, Int),
+    fchmod = 91(Int, Int),
+    chown = 92(Ptr, Int, Int),
+    fchown = 93(Int, Int, Int),
+    umask = 95(Int),
+    gettimeofday = 96(Ptr, Ptr),
+    getrlimit = 97(Int, Ptr),
+    getrusage = 98(Int, Ptr),
+    sysinfo = 99(Ptr),
+    times = 100(Ptr),
+    getuid = 102(),
+    getgid = 104(),
+    setuid = 105(Int),
+    setgid = 106(Int),
+    geteuid = 107(),
+    getegid = 108(),
+    setpgid = 109(Int, Int),
+    getppid = 110(),
+    getpgrp = 111(),
+    setsid = 112(),
+    getpgid = 121(Int),
+    getsid = 124(Int),
+    sigaltstack = 131(Ptr, Ptr),
+    prctl = 157(Int, Int, Int, Int, Int),
+    arch_prctl = 158(Int, Ptr),
+    setrlimit = 160(Int, Ptr),
+    sync = 162(),
+    gettid = 186(),
+    tkill = 200(Int, Int),
+    time = 201(Ptr),
+    futex = 202(Ptr, Int, Int, Ptr, Ptr, Int),
+    sched_setaffinity = 203(Int, Int, Ptr),
+    sched_getaffinity = 204(Int, Int, Ptr),
+    getdents64 = 217(Int, Ptr, Int),
+    set_tid_address = 218(Ptr),
+    clock_gettime = 228(Int, Ptr),
+    clock_getres = 229(Int, Ptr),
+    clock_nanosleep = 230(Int, Int, Ptr, Ptr),
+    exit_group = 231(Int),
+    epoll_wait = 232(Int, Ptr, Int, Int),
+    epoll_ctl = 233(Int, Int, Int, Ptr),
+    tgkill = 234(Int, Int, Int),
+    openat = 257(Int, Ptr, Int, Int),
+    mkdirat = 258(Int, Ptr, Int),
+    newfstatat = 262(Int, Ptr, Ptr, Int),
+    unlinkat = 263(Int, Ptr, Int),
+    renameat = 264(Int, Ptr, Int, Ptr),
+    readlinkat = 267(Int, Ptr, Ptr, Int),
+    faccessat = 269(Int, Ptr, Int),
+    pselect6 = 270(Int, Ptr, Ptr, Ptr, Ptr, Ptr),
+    ppoll = 271(Ptr, Int, Ptr, Ptr, Int),
+    set_robust_list = 273(Ptr, Int),
+    get_robust_list = 274(Int, Ptr, Ptr),
+    epoll_pwait = 281(Int, Ptr, Int, Int, Ptr, Int),
+    eventfd2 = 290(Int, Int),
+    epoll_create1 = 291(Int),
+    dup3 = 292(Int, Int, Int),
+    pipe2 = 293(Ptr, Int),
+    prlimit64 = 302(Int, Int, Ptr, Ptr),
+    getrandom = 318(Ptr, Int, Int),
+    memfd_create = 319(Ptr, Int),
+    statx = 332(Int, Ptr, Int, Int, Ptr),
+    clone3 = 435(Ptr, Int),
+];
+
+pub fn by_name(name: &str) -> Option<&'static SyscallSignature> {
+    SYSCALLS.iter().find(|syscall| syscall.name == name)
+}
+
+pub fn by_nr(nr: u64) -> Option<&'static SyscallSignature> {
+    SYSCALLS.iter().find(|syscall| syscall.nr == nr)
+}
+
+/// Syscalls fail by returning `-errno`, with `errno` in `1..=MAX_ERRNO`
+pub const MAX_ERRNO: u64 = 4095;
+
+/// Linux error numbers, from `include/uapi/asm-generic/errno-base.h` and `errno.h`
+pub const ERRNOS: &[(&str, u64)] = &[
+    ("EPERM", 1),
+    ("ENOENT", 2),
+    ("ESRCH", 3),
+    ("EINTR", 4),
+    ("EIO", 5),
+    ("ENXIO", 6),
+    ("E2BIG", 7),
+    ("ENOEXEC", 8),
+    ("EBADF", 9),
+    ("ECHILD", 10),
+    ("EAGAIN", 11),
+    ("EWOULDBLOCK", 11),
+    ("ENOMEM", 12),
+    ("EACCES", 13),
+    ("EFAULT", 14),
+    ("ENOTBLK", 15),
+    ("EBUSY", 16),
+    ("EEXIST", 17),
+    ("EXDEV", 18),
+    ("ENODEV", 19),
+    ("ENOTDIR", 20),
+    ("EISDIR", 21),
+    ("EINVAL", 22),
+    ("ENFILE", 23),
+    ("EMFILE", 24),
+    ("ENOTTY", 25),
+    ("ETXTBSY", 26),
+    ("EFBIG", 27),
+    ("ENOSPC", 28),
+    ("ESPIPE", 29),
+    ("EROFS", 30),
+    ("EMLINK", 31),
+    ("EPIPE", 32),
+    ("EDOM", 33),
+    ("ERANGE", 34),
+    ("EDEADLK", 35),
+    ("ENAMETOOLONG", 36),
+    ("ENOLCK", 37),
+    ("ENOSYS", 38),
+    ("ENOTEMPTY", 39),
+    ("ELOOP", 40),
+    ("ENOMSG", 42),
+    ("EIDRM", 43),
+    ("ENODATA", 61),
+    ("ETIME", 62),
+    ("EOVERFLOW", 75),
+    ("EILSEQ", 84),
+    ("ENOTSOCK", 88),
+    ("EDESTADDRREQ", 89),
+    ("EMSGSIZE", 90),
+    ("EPROTOTYPE", 91),
+    ("ENOPROTOOPT", 92),
+    ("EPROTONOSUPPORT", 93),
+    ("EOPNOTSUPP", 95),
+    ("EAFNOSUPPORT", 97),
+    ("EADDRINUSE", 98),
+    ("EADDRNOTAVAIL", 99),
+    ("ENETDOWN", 100),
+    ("ENETUNREACH", 101),
+    ("ECONNABORTED", 103),
+    ("ECONNRESET", 104),
+    ("ENOBUFS", 105),
+    ("EISCONN", 106),
+    ("ENOTCONN", 107),
+    ("ETIMEDOUT", 110),
+    ("ECONNREFUSED", 111),
+    ("EHOSTUNREACH", 113),
+    ("EALREADY", 114),
+    ("EINPROGRESS", 115),
+    ("ECANCELED", 125),
+];
+
+pub fn errno_by_name(name: &str) -> Option<u64> {
+    ERRNOS
+        .iter()
+        .find(|(errno, _)| *errno == name)
+        .map(|&(_, val)| val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let write = by_name("write").unwrap();
+        assert_eq!(write.nr, 1);
+        assert_eq!(write.args, [Int, Ptr, Int]);
+        assert_eq!(by_name("exit_group").unwrap().nr, 231);
+        assert_eq!(by_nr(56).unwrap().name, "clone");
+        assert_eq!(by_nr(202).unwrap().args.len(), 6);
+        assert!(by_name("fork2").is_none());
+        assert!(by_nr(29).is_none());
+    }
+
+    #[test]
+    fn names_and_numbers_are_unique() {
+        for syscall in SYSCALLS {
+            assert_eq!(by_name(syscall.name).unwrap().nr, syscall.nr);
+            assert_eq!(by_nr(syscall.nr).unwrap().name, syscall.name);
+        }
+    }
+
+    #[test]
+    fn arg_kinds() {
+        for ty in [Type::I8, Type::I32, Type::I64, Type::U16, Type::U64] {
+            assert!(Int.accepts(&ty), "{ty:?}");
+        }
+        for ty in [Type::Ptr, Type::F64, Type::VaList, Type::Tuple(vec![])] {
+            assert!(!Int.accepts(&ty), "{ty:?}");
+        }
+        for ty in [Type::Ptr, Type::I64, Type::U64] {
+            assert!(Ptr.accepts(&ty), "{ty:?}");
+        }
+        for ty in [Type::I32, Type::U8, Type::F64, Type::Enum("e".into())] {
+            assert!(!Ptr.accepts(&ty), "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn errnos() {
+        assert_eq!(errno_by_name("EPERM"), Some(1));
+        assert_eq!(errno_by_name("EAGAIN"), Some(11));
+        assert_eq!(errno_by_name("EINVAL"), Some(22));
+        assert_eq!(errno_by_name("EFOO"), None);
+        for &(name, errno) in ERRNOS {
+            assert!((1..=MAX_ERRNO).contains(&errno), "{name}");
+            assert_eq!(errno_by_name(name), Some(errno));
+        }
+    }
+}
@@ -1,57 +1,476 @@
-use std::{fmt::Debug, fs::File, hash::Hash};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Debug,
+    fs::File,
+    hash::Hash,
+    path::PathBuf,
+};
 
 use crate::{
     codegen::generic::Codegen,
-    data::St,
-    json::{Expression, Statement},
+    data::{EnumStEntry, FunctionStEntry, St, Type},
+    json::{BinOp, CmpOp, DataItem, ErrorArm, Expression, Location, MatchArm, Statement, Syscall},
 };
 
+mod runtime;
+
+use runtime::Routine;
+
 use super::{
     elf::ElfFileBuilder,
     ir::{
-        syscall::{ARG_REGISTERS, SYSCALL_REG, STACK_FRAME_POINTER, STACK_POINTER},
-        Constant, Instr, RegAllocation, RegisterAllocator, RegisterRequest, Register,
+        syscall::{
+            ARG_REGISTERS, CALL_ARG_REGISTERS, FLOAT_ARG_REGISTERS, FLOAT_RETURN_REG,
+            FLOAT_RETURN_REGISTERS, INDIRECT_CALL_REG, RETURN_REG, RETURN_REGISTERS,
+            STACK_FRAME_POINTER, STACK_POINTER, SYSCALL_REG,
+        },
+        syscall_table, Condition, Constant, FloatOp, FloatSize, Instr, IntOp, IntSize,
+        RegAllocation, Register, RegisterAllocator, RegisterRequest, XmmAllocation, XmmRegister,
+        XmmRequest,
     },
     optimizer::Optimizer,
 };
 
+#[cfg(test)]
+mod tests;
+
 pub const PAGE_SIZE: u64 = 4096; // 4kb
 
+/// Exit status of programs stopped by a failed arithmetic check, the one shells report for
+/// processes killed by SIGFPE
+const ARITHMETIC_TRAP_EXIT_CODE: u64 = 128 + 8;
+/// Exit status of programs stopped by [`Statement::Panic`]
+const PANIC_EXIT_CODE: u64 = 101;
+/// Exit status of programs stopped by a clobbered stack canary, the one shells report for
+/// processes killed by SIGABRT
+const STACK_SMASHING_EXIT_CODE: u64 = 128 + 6;
+
+/// Where the process starts running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// Call the named function from the generated `_start` with argc, argv & envp, exiting with
+    /// what it returns
+    Main(String),
+    /// Use the named function as `_start`, jumping to it once the runtime is initialized. It
+    /// takes no arguments and must exit by itself, as it has nowhere to return to
+    Start(String),
+    /// No entry point, for code only ever called into by others
+    None,
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Self::Main("main".to_string())
+    }
+}
+
+/// Settings changing the code generated for a whole program
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Function the program starts from
+    pub entry: Entry,
+    /// Trap on integer overflow and division by zero, naming the function on stderr
+    pub checked_arithmetic: bool,
+    /// Guard the frames of functions holding aggregates with a canary, checked before they
+    /// return
+    pub stack_protector: bool,
+    /// Run `main` on a stack of this many bytes mapped at startup, below a guard page, instead
+    /// of the one the kernel set up
+    pub main_stack_size: Option<u64>,
+}
+
+/// Size of each stack slot in the frame of a function
+const SLOT_SIZE: u64 = 8;
+
+/// Slot of the stack canary, right below the saved frame pointer, when the stack protector is
+/// enabled
+const CANARY_OFFSET: i32 = -(SLOT_SIZE as i32);
+
+/// Size of the area variadic functions spill the argument registers into: 8 bytes for each
+/// integer register followed by 16 for each float one
+const REG_SAVE_AREA_SIZE: u64 =
+    CALL_ARG_REGISTERS.len() as u64 * 8 + FLOAT_ARG_REGISTERS.len() as u64 * 16;
+/// Size of a `va_list`: the `u32` offsets of the next integer & float arguments in the register
+/// save area, the address of the next argument passed on the stack and that of the register
+/// save area
+const VA_LIST_SIZE: u64 = 24;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 enum LabelId<StEntryId> {
     StLabel(StEntryId),
     CustomLabel(usize),
     DataAddr(u64),
+    /// Offset in the writable data segment
+    RwDataAddr(u64),
+    /// Size of the frame of a function, known once all of its scopes have been generated
+    FrameSize(StEntryId),
+    Runtime(Routine),
+}
+
+/// Code of a function that's either run or jumped over, as the jump and the labels around it
+type OptionalBlock<SE> = (LabelId<SE>, LabelId<SE>, LabelId<SE>);
+
+/// A failed runtime check, jumped to through a trap pad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Trap {
+    Overflow,
+    DivisionByZero,
+    StackSmashing,
+}
+
+impl Trap {
+    const fn message(self) -> &'static str {
+        match self {
+            Self::Overflow => "arithmetic overflow",
+            Self::DivisionByZero => "division by zero",
+            Self::StackSmashing => "stack smashing detected",
+        }
+    }
+
+    const fn exit_code(self) -> u64 {
+        match self {
+            Self::Overflow | Self::DivisionByZero => ARITHMETIC_TRAP_EXIT_CODE,
+            Self::StackSmashing => STACK_SMASHING_EXIT_CODE,
+        }
+    }
+}
+
+/// Operations on rdx:rax
+#[derive(Debug, Clone, Copy)]
+enum WideOp<Lbl> {
+    Div {
+        signed: bool,
+    },
+    Rem {
+        signed: bool,
+    },
+    /// Jumping to `overflow` if the result doesn't fit in 64 bits
+    CheckedUnsignedMul {
+        overflow: Lbl,
+    },
+}
+
+/// An address to patch into a data segment once the layout of the program is known
+struct DataRelocation<StEntryId> {
+    /// Blob holding the address
+    blob: LabelId<StEntryId>,
+    /// Offset of the address in the blob
+    offset: usize,
+    target: LabelId<StEntryId>,
+}
+
+/// A function body or block, with the stack slots of the variables it declares
+struct Scope<StEntryId> {
+    st: usize,
+    slots: HashMap<StEntryId, i32>,
+    /// `frame_top` when the scope was entered, so that its slots can be reused once it ends
+    prev_frame_top: u64,
+}
+
+#[derive(Debug)]
+enum ValueReg {
+    Gp(RegAllocation),
+    Xmm(XmmAllocation),
+}
+
+/// The result of an expression, held in a register of the class its type requires
+#[derive(Debug)]
+struct Value {
+    ty: Type,
+    reg: ValueReg,
+}
+
+impl Value {
+    const fn gp(ty: Type, reg: RegAllocation) -> Self {
+        Self {
+            ty,
+            reg: ValueReg::Gp(reg),
+        }
+    }
+
+    const fn xmm(ty: Type, reg: XmmAllocation) -> Self {
+        Self {
+            ty,
+            reg: ValueReg::Xmm(reg),
+        }
+    }
+
+    fn into_gp(self) -> RegAllocation {
+        match self.reg {
+            ValueReg::Gp(reg) => reg,
+            ValueReg::Xmm(_) => panic!("Expected an integer value, got {:?}", self.ty),
+        }
+    }
+
+    fn into_xmm(self) -> XmmAllocation {
+        match self.reg {
+            ValueReg::Xmm(reg) => reg,
+            ValueReg::Gp(_) => panic!("Expected a float value, got {:?}", self.ty),
+        }
+    }
+}
+
+const fn float_size(ty: &Type) -> FloatSize {
+    match ty {
+        Type::F32 => FloatSize::Single,
+        Type::F64 => FloatSize::Double,
+        _ => panic!("Not a float type"),
+    }
+}
+
+/// Size and signedness of integer types narrower than a register
+const fn narrow_int(ty: &Type) -> Option<(IntSize, bool)> {
+    match ty.int_repr() {
+        Some((8, signed)) => Some((IntSize::Byte, signed)),
+        Some((16, signed)) => Some((IntSize::Word, signed)),
+        Some((32, signed)) => Some((IntSize::Dword, signed)),
+        _ => None,
+    }
+}
+
+/// Where an argument is passed, following the SysV classification
+enum ArgLocation {
+    Gp(RegisterRequest),
+    Xmm(XmmRequest),
+}
+
+/// What a call jumps to
+enum CallTarget<StEntryId> {
+    Direct(StEntryId),
+    /// An address computed at runtime, whose signature is given by the argument types and `ret`
+    Indirect {
+        addr: Expression<StEntryId>,
+        ret: Type,
+        variadic: bool,
+    },
+    Runtime(Routine),
+}
+
+/// A call whose arguments are in place, ready to be jumped to
+struct StagedCall<StEntryId> {
+    /// The called function, or `None` if its address is in `addr`
+    label: Option<LabelId<StEntryId>>,
+    addr: Option<RegAllocation>,
+    args: Vec<ValueReg>,
+    /// Register of the hidden return pointer, left for the caller to set
+    ret_ptr: Option<RegAllocation>,
+    /// `al`, holding the number of float registers used, for calls to variadic functions
+    float_count: Option<RegAllocation>,
+    /// Types of the arguments as evaluated, before `va_list`s are passed by address
+    arg_types: Vec<Type>,
+}
+
+fn arg_locations(args: &[Type]) -> Vec<ArgLocation> {
+    let mut gp = CALL_ARG_REGISTERS.iter().copied();
+    let mut xmm = FLOAT_ARG_REGISTERS.iter().copied();
+    args.iter()
+        .map(|ty| {
+            if ty.is_float() {
+                ArgLocation::Xmm(xmm.next().expect("Too many float arguments"))
+            } else {
+                ArgLocation::Gp(gp.next().expect("Too many integer arguments"))
+            }
+        })
+        .collect()
 }
 
 pub struct X64LinuxElfCodegen<SE: PartialEq + Eq + Hash, S: St<StEntryId = SE>> {
+    options: Options,
     data: Vec<u8>,
     opt: Optimizer<LabelId<S::StEntryId>>,
     st: S,
     registers: RegisterAllocator,
-    main: Option<LabelId<S::StEntryId>>,
+    /// The function named by [`Options::entry`], once defined
+    entry: Option<SE>,
     data_labels: Vec<u64>,
+    rw_data: Vec<u8>,
+    rw_data_labels: Vec<u64>,
+    data_relocations: Vec<DataRelocation<SE>>,
+    current_fn: Option<SE>,
+    /// Bytes pushed below the (16 byte aligned) frame of the current function
+    stack_depth: u64,
+    scopes: Vec<Scope<SE>>,
+    /// Bytes of the frame used by the slots of the active scopes
+    frame_top: u64,
+    /// Bytes of the frame used by the slots of any scope of the current function so far
+    frame_size: u64,
+    next_label: usize,
+    /// Labels of the current function by name, and whether they have been placed yet
+    fn_labels: HashMap<String, (LabelId<SE>, bool)>,
+    /// Landing pads of the failed checks of the current function
+    fn_traps: Vec<(Trap, LabelId<SE>)>,
+    /// Whether the current function holds aggregates on the stack, so needs a canary
+    fn_buffers: bool,
+    /// Code handling the canary of the current function
+    fn_canary_blocks: Vec<OptionalBlock<SE>>,
+    /// Probes of the stack pages the frame of the current function spans, only run when it's
+    /// larger than a page
+    fn_probe_block: Option<OptionalBlock<SE>>,
+    /// Writable word holding the stack canary, and whether any function checks it
+    canary: Option<u64>,
+    stack_protected: bool,
+    /// Routines of the runtime to emit, and the writable words they own
+    runtime_used: BTreeSet<Routine>,
+    runtime_slots: HashMap<Routine, u64>,
+    /// Functions called or whose address is taken, and those with a body
+    referenced_fns: HashSet<SE>,
+    defined_fns: HashSet<SE>,
+    /// Where the executable is written to
+    output: PathBuf,
 }
 
-impl<SE: PartialEq + Eq + Hash + Debug, S: St<StEntryId = SE>> X64LinuxElfCodegen<SE, S> {
-    pub fn new(st: S) -> Self {
+impl<SE: PartialEq + Eq + Hash + Clone + Copy + Debug, S: St<StEntryId = SE>>
+    X64LinuxElfCodegen<SE, S>
+{
+    pub fn with_options(st: S, options: Options) -> Self {
+        // The canary is seeded at startup, which needs an entry point
+        assert!(
+            !(options.stack_protector && options.entry == Entry::None),
+            "The stack protector can't be used without an entry point"
+        );
         Self {
+            options,
             data: Vec::new(),
             opt: Optimizer::new(0x10000),
             st,
             registers: RegisterAllocator::new(),
-            main: None,
+            entry: None,
             data_labels: Vec::new(),
+            rw_data: Vec::new(),
+            rw_data_labels: Vec::new(),
+            data_relocations: Vec::new(),
+            current_fn: None,
+            stack_depth: 0,
+            scopes: Vec::new(),
+            frame_top: 0,
+            frame_size: 0,
+            next_label: 0,
+            fn_labels: HashMap::new(),
+            fn_traps: Vec::new(),
+            fn_buffers: false,
+            fn_canary_blocks: Vec::new(),
+            fn_probe_block: None,
+            canary: None,
+            stack_protected: false,
+            runtime_used: BTreeSet::new(),
+            runtime_slots: HashMap::new(),
+            referenced_fns: HashSet::new(),
+            defined_fns: HashSet::new(),
+            output: PathBuf::from("res.elf"),
+        }
+    }
+
+    fn new_label(&mut self) -> LabelId<SE> {
+        let label = LabelId::CustomLabel(self.next_label);
+        self.next_label += 1;
+        label
+    }
+
+    fn add_data(&mut self, bytes: &[u8], align: usize) -> u64 {
+        while !self.data.len().is_multiple_of(align) {
+            self.data.push(0);
         }
+        let addr = self.data.len() as u64;
+        self.data_labels.push(addr);
+        self.data.extend_from_slice(bytes);
+        addr
     }
 
-    fn gen_expression(&mut self, expr: Expression) -> RegAllocation {
+    /// Label of the function `func`, which must be defined or provided by the runtime
+    fn fn_ref(&mut self, func: SE) -> LabelId<SE> {
+        self.referenced_fns.insert(func);
+        LabelId::StLabel(func)
+    }
+
+    /// The label named `name` in the current function, created on its first use
+    fn fn_label(&mut self, name: &str) -> &mut (LabelId<SE>, bool) {
+        if !self.fn_labels.contains_key(name) {
+            let label = self.new_label();
+            self.fn_labels.insert(name.to_string(), (label, false));
+        }
+        self.fn_labels.get_mut(name).unwrap()
+    }
+
+    fn add_rw_data(&mut self, bytes: &[u8], align: usize) -> u64 {
+        while !self.rw_data.len().is_multiple_of(align) {
+            self.rw_data.push(0);
+        }
+        let addr = self.rw_data.len() as u64;
+        self.rw_data_labels.push(addr);
+        self.rw_data.extend_from_slice(bytes);
+        addr
+    }
+
+    /// Lays out the items of a blob into its data segment, returning its label
+    fn add_blob(&mut self, items: Vec<DataItem<SE>>, mutable: bool) -> LabelId<SE> {
+        fn align_to(bytes: &mut Vec<u8>, align: usize) {
+            while !bytes.len().is_multiple_of(align) {
+                bytes.push(0);
+            }
+        }
+        let mut bytes = Vec::new();
+        let mut relocations = Vec::new();
+        for item in items {
+            match item {
+                DataItem::Bytes { val } => bytes.extend_from_slice(&val),
+                DataItem::Str { val } => bytes.extend_from_slice(val.as_bytes()),
+                DataItem::Int { val, ty } => {
+                    let (width, _) = ty
+                        .int_repr()
+                        .unwrap_or_else(|| panic!("Integer data of non integer type {ty:?}"));
+                    let size = width as usize / 8;
+                    align_to(&mut bytes, size);
+                    bytes.extend_from_slice(&val.to_le_bytes()[..size]);
+                }
+                DataItem::Float { val, ty } => match ty {
+                    Type::F32 => {
+                        align_to(&mut bytes, 4);
+                        bytes.extend_from_slice(&(val as f32).to_le_bytes());
+                    }
+                    Type::F64 => {
+                        align_to(&mut bytes, 8);
+                        bytes.extend_from_slice(&val.to_le_bytes());
+                    }
+                    ty => panic!("Float data of non float type {ty:?}"),
+                },
+                DataItem::FnAddr { func } => {
+                    align_to(&mut bytes, 8);
+                    relocations.push((bytes.len(), self.fn_ref(func)));
+                    bytes.extend_from_slice(&[0; 8]);
+                }
+                DataItem::Data { items, mutable } => {
+                    let target = self.add_blob(items, mutable);
+                    align_to(&mut bytes, 8);
+                    relocations.push((bytes.len(), target));
+                    bytes.extend_from_slice(&[0; 8]);
+                }
+            }
+        }
+        let blob = if mutable {
+            LabelId::RwDataAddr(self.add_rw_data(&bytes, 8))
+        } else {
+            LabelId::DataAddr(self.add_data(&bytes, 8))
+        };
+        self.data_relocations
+            .extend(
+                relocations
+                    .into_iter()
+                    .map(|(offset, target)| DataRelocation {
+                        blob,
+                        offset,
+                        target,
+                    }),
+            );
+        blob
+    }
+
+    fn gen_expression(&mut self, expr: Expression<SE>) -> Value {
         match expr {
             Expression::IntConst { val } => {
                 let reg = self.registers.allocate_any();
                 self.opt
                     .accept(Instr::SetConstant(reg.reg(), Constant::Value(val)));
-                reg
+                Value::gp(Type::I64, reg)
             }
             Expression::StrConst { val } => {
                 let reg = self.registers.allocate_any();
@@ -63,9 +482,1909 @@ impl<SE: PartialEq + Eq + Hash + Debug, S: St<StEntryId = SE>> X64LinuxElfCodege
                 ));
                 self.data.extend_from_slice(val.as_bytes());
                 self.data.push(0);
+                Value::gp(Type::Ptr, reg)
+            }
+            Expression::StrLen { val } => {
+                let reg = self.registers.allocate_any();
+                self.opt.accept(Instr::SetConstant(
+                    reg.reg(),
+                    Constant::Value(val.len() as u64),
+                ));
+                Value::gp(Type::I64, reg)
+            }
+            Expression::FloatConst { val, ty } => {
+                let addr = match ty {
+                    Type::F32 => self.add_data(&(val as f32).to_le_bytes(), 4),
+                    Type::F64 => self.add_data(&val.to_le_bytes(), 8),
+                    ref ty => panic!("Float constant of non float type {ty:?}"),
+                };
+                let ptr = self.registers.allocate_any();
+                self.opt.accept(Instr::SetConstant(
+                    ptr.reg(),
+                    Constant::Tbd(LabelId::DataAddr(addr)),
+                ));
+                let reg = self.registers.allocate_any_xmm();
+                self.opt.accept(Instr::LoadXmm {
+                    size: float_size(&ty),
+                    dest: reg.reg(),
+                    base: ptr.reg(),
+                    offset: 0,
+                });
+                self.free(ptr);
+                Value::xmm(ty, reg)
+            }
+            Expression::Arg { idx } => {
+                let ty = self.current_args()[idx].clone();
+                if ty == Type::VaList {
+                    // Received by address
+                    let reg = self.registers.allocate_any();
+                    self.opt.accept(Instr::Load {
+                        dest: reg.reg(),
+                        base: STACK_FRAME_POINTER,
+                        offset: self.arg_offset(idx),
+                    });
+                    return Value::gp(ty, reg);
+                }
+                self.load_slot(ty, self.arg_offset(idx))
+            }
+            Expression::Var { name } => {
+                let (ty, offset) = self.resolve(&name);
+                self.load_slot(ty, offset)
+            }
+            Expression::BinOp { op, lhs, rhs } => {
+                let lhs = self.gen_expression(*lhs);
+                let rhs = self.gen_expression(*rhs);
+                assert_eq!(lhs.ty, rhs.ty, "Mismatched operand types for {op:?}");
+                assert!(lhs.ty.is_scalar(), "Arithmetic on {:?}", lhs.ty);
+                let ty = lhs.ty.clone();
+                if ty.is_float() {
+                    let op = match op {
+                        BinOp::Add => FloatOp::Add,
+                        BinOp::Sub => FloatOp::Sub,
+                        BinOp::Mul => FloatOp::Mul,
+                        BinOp::Div => FloatOp::Div,
+                        BinOp::Rem => panic!("Remainder isn't supported for floats"),
+                    };
+                    let (lhs, rhs) = (lhs.into_xmm(), rhs.into_xmm());
+                    self.opt.accept(Instr::FloatOp {
+                        op,
+                        size: float_size(&ty),
+                        dest: lhs.reg(),
+                        orig: rhs.reg(),
+                    });
+                    self.free_xmm(rhs);
+                    Value::xmm(ty, lhs)
+                } else {
+                    let (lhs, rhs) = (lhs.into_gp(), rhs.into_gp());
+                    let (_, signed) = ty.int_repr().expect("Arithmetic on a non integer type");
+                    // Pointer arithmetic is allowed to wrap around
+                    let checked = self.options.checked_arithmetic && ty != Type::Ptr;
+                    let narrow = narrow_int(&ty).is_some();
+                    let res = match op {
+                        BinOp::Div | BinOp::Rem => {
+                            if checked {
+                                self.check_divisor(lhs.reg(), rhs.reg(), &ty);
+                            }
+                            let op = if op == BinOp::Div {
+                                WideOp::Div { signed }
+                            } else {
+                                WideOp::Rem { signed }
+                            };
+                            self.gen_wide(lhs, rhs, op)
+                        }
+                        BinOp::Mul if checked && !signed && !narrow => {
+                            let overflow = self.trap_label(Trap::Overflow);
+                            self.gen_wide(lhs, rhs, WideOp::CheckedUnsignedMul { overflow })
+                        }
+                        _ => {
+                            let op = match op {
+                                BinOp::Add => IntOp::Add,
+                                BinOp::Sub => IntOp::Sub,
+                                _ => IntOp::Mul,
+                            };
+                            self.opt.accept(Instr::IntOp {
+                                op,
+                                dest: lhs.reg(),
+                                orig: rhs.reg(),
+                            });
+                            self.free(rhs);
+                            if checked && !narrow {
+                                // The carry flag is set by unsigned additions & subtractions
+                                // that wrap around
+                                let cond = if signed {
+                                    Condition::Overflow
+                                } else {
+                                    Condition::Below
+                                };
+                                let trap = self.trap_label(Trap::Overflow);
+                                self.opt.accept(Instr::JumpIf(cond, trap));
+                            }
+                            lhs
+                        }
+                    };
+                    // Narrow operands are extended to 64 bits, so their results never overflow
+                    // the register, but may not fit the type
+                    if checked && narrow {
+                        self.check_fits(res.reg(), &ty);
+                    }
+                    // Wrap around results of narrow types
+                    self.canonicalize(res.reg(), &ty);
+                    Value::gp(ty, res)
+                }
+            }
+            Expression::Cmp { op, lhs, rhs } => {
+                let lhs = self.gen_expression(*lhs);
+                let rhs = self.gen_expression(*rhs);
+                assert_eq!(lhs.ty, rhs.ty, "Mismatched operand types for {op:?}");
+                assert!(lhs.ty.is_scalar(), "Comparison of {:?}", lhs.ty);
+                if lhs.ty.is_float() {
+                    let size = float_size(&lhs.ty);
+                    let (lhs, rhs) = (lhs.into_xmm(), rhs.into_xmm());
+                    let res = self.gen_float_cmp(op, size, lhs.reg(), rhs.reg());
+                    self.free_xmm(lhs);
+                    self.free_xmm(rhs);
+                    Value::gp(Type::I64, res)
+                } else {
+                    let (_, signed) = lhs.ty.int_repr().expect("Comparison of non integer types");
+                    let (lhs, rhs) = (lhs.into_gp(), rhs.into_gp());
+                    let cond = match (op, signed) {
+                        (CmpOp::Eq, _) => Condition::Equal,
+                        (CmpOp::Ne, _) => Condition::NotEqual,
+                        (CmpOp::Lt, true) => Condition::Less,
+                        (CmpOp::Le, true) => Condition::LessOrEqual,
+                        (CmpOp::Gt, true) => Condition::Greater,
+                        (CmpOp::Ge, true) => Condition::GreaterOrEqual,
+                        (CmpOp::Lt, false) => Condition::Below,
+                        (CmpOp::Le, false) => Condition::BelowOrEqual,
+                        (CmpOp::Gt, false) => Condition::Above,
+                        (CmpOp::Ge, false) => Condition::AboveOrEqual,
+                    };
+                    self.opt.accept(Instr::Compare {
+                        lhs: lhs.reg(),
+                        rhs: rhs.reg(),
+                    });
+                    self.opt.accept(Instr::SetCondition(cond, lhs.reg()));
+                    self.free(rhs);
+                    Value::gp(Type::I64, lhs)
+                }
+            }
+            Expression::Convert { expr, to } => {
+                let value = self.gen_expression(*expr);
+                assert!(
+                    value.ty.is_scalar() && to.is_scalar(),
+                    "Conversion from {:?} to {to:?}",
+                    value.ty
+                );
+                match (value.ty.is_float(), to.is_float()) {
+                    _ if value.ty == to => value,
+                    (false, true) => {
+                        let unsigned = value.ty.int_repr() == Some((64, false));
+                        let orig = value.into_gp();
+                        let dest = self.registers.allocate_any_xmm();
+                        if unsigned {
+                            self.gen_u64_to_float(float_size(&to), dest.reg(), orig.reg());
+                        } else {
+                            self.opt.accept(Instr::IntToFloat {
+                                size: float_size(&to),
+                                dest: dest.reg(),
+                                orig: orig.reg(),
+                            });
+                        }
+                        self.free(orig);
+                        Value::xmm(to, dest)
+                    }
+                    (true, false) => {
+                        let ty = value.ty.clone();
+                        let orig = value.into_xmm();
+                        let dest = self.registers.allocate_any();
+                        if to.int_repr() == Some((64, false)) {
+                            self.gen_float_to_u64(ty, dest.reg(), orig.reg());
+                        } else {
+                            self.opt.accept(Instr::FloatToInt {
+                                size: float_size(&ty),
+                                dest: dest.reg(),
+                                orig: orig.reg(),
+                            });
+                        }
+                        self.free_xmm(orig);
+                        self.canonicalize(dest.reg(), &to);
+                        Value::gp(to, dest)
+                    }
+                    (true, true) => {
+                        let reg = value.into_xmm();
+                        self.opt.accept(Instr::FloatResize {
+                            to: float_size(&to),
+                            dest: reg.reg(),
+                            orig: reg.reg(),
+                        });
+                        Value::xmm(to, reg)
+                    }
+                    (false, false) => self.gen_cast(value, to),
+                }
+            }
+            Expression::Cast { expr, to } => {
+                let value = self.gen_expression(*expr);
+                self.gen_cast(value, to)
+            }
+            Expression::Call { func, args } => self.gen_call(CallTarget::Direct(func), args),
+            Expression::CallIndirect {
+                addr,
+                args,
+                ret,
+                variadic,
+            } => self.gen_call(
+                CallTarget::Indirect {
+                    addr: *addr,
+                    ret,
+                    variadic,
+                },
+                args,
+            ),
+            Expression::Auxv { key } => {
+                self.gen_call(CallTarget::Runtime(Routine::Auxv), vec![*key])
+            }
+            Expression::ClockGettime { clock, ts } => self.gen_call(
+                CallTarget::Runtime(Routine::ClockGettime),
+                vec![*clock, *ts],
+            ),
+            Expression::TrySyscall { syscall, args } => self.gen_try_syscall_expr(syscall, args),
+            Expression::Errno { name } => {
+                let val = syscall_table::errno_by_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown errno {name}"));
+                self.gen_expression(Expression::IntConst { val })
+            }
+            Expression::FnAddr { func } => {
+                assert!(
+                    self.st.get(func).kind.unwrap_function().is_some(),
+                    "Taking the address of a symbol that isn't a function"
+                );
+                let label = self.fn_ref(func);
+                let reg = self.registers.allocate_any();
+                self.opt
+                    .accept(Instr::SetConstant(reg.reg(), Constant::Tbd(label)));
+                Value::gp(Type::Ptr, reg)
+            }
+            Expression::Construct {
+                ty,
+                variant,
+                fields,
+            } => {
+                let def = self.resolve_enum(&ty);
+                let size = def.size();
+                let (tag, variant) = def
+                    .variant(&variant)
+                    .unwrap_or_else(|| panic!("{ty} has no variant {variant}"));
+                let field_types = variant.fields.clone();
+                assert_eq!(fields.len(), field_types.len(), "Wrong number of fields");
+                let offset = self.alloc_buffer(size);
+                let reg = self.registers.allocate_any();
+                self.opt
+                    .accept(Instr::SetConstant(reg.reg(), Constant::Value(tag)));
+                self.opt.accept(Instr::Store {
+                    base: STACK_FRAME_POINTER,
+                    offset,
+                    orig: reg.reg(),
+                });
+                self.free(reg);
+                for (i, (expr, field_ty)) in fields.into_iter().zip(field_types).enumerate() {
+                    let value = self.gen_expression(expr);
+                    assert_eq!(value.ty, field_ty, "Mismatched field type");
+                    let field_offset = offset + Self::field_offset(i);
+                    self.store_mem(value, STACK_FRAME_POINTER, field_offset);
+                }
+                let reg = self.registers.allocate_any();
+                self.opt.accept(Instr::LoadAddress {
+                    dest: reg.reg(),
+                    base: STACK_FRAME_POINTER,
+                    offset,
+                });
+                Value::gp(Type::Enum(ty), reg)
+            }
+            Expression::Tuple { elems } => {
+                let offset = self.alloc_buffer(elems.len() as u64 * SLOT_SIZE);
+                let mut types = Vec::with_capacity(elems.len());
+                for (i, expr) in elems.into_iter().enumerate() {
+                    let value = self.gen_expression(expr);
+                    assert!(
+                        value.ty.is_scalar(),
+                        "Tuples of {:?} aren't supported",
+                        value.ty
+                    );
+                    types.push(value.ty.clone());
+                    self.store_mem(value, STACK_FRAME_POINTER, offset + Self::elem_offset(i));
+                }
+                let reg = self.registers.allocate_any();
+                self.opt.accept(Instr::LoadAddress {
+                    dest: reg.reg(),
+                    base: STACK_FRAME_POINTER,
+                    offset,
+                });
+                Value::gp(Type::Tuple(types), reg)
+            }
+            Expression::Field { expr, idx } => {
+                let value = self.gen_expression(*expr);
+                let Type::Tuple(types) = &value.ty else {
+                    panic!("Accessing a field of {:?}, which isn't a tuple", value.ty)
+                };
+                let ty = types
+                    .get(idx)
+                    .unwrap_or_else(|| panic!("{:?} has no field {idx}", value.ty))
+                    .clone();
+                let addr = value.into_gp();
+                let field = self.load_mem(ty, addr.reg(), Self::elem_offset(idx));
+                self.free(addr);
+                field
+            }
+            Expression::Data { items, mutable } => {
+                let blob = self.add_blob(items, mutable);
+                let reg = self.registers.allocate_any();
+                self.opt
+                    .accept(Instr::SetConstant(reg.reg(), Constant::Tbd(blob)));
+                Value::gp(Type::Ptr, reg)
+            }
+            Expression::Load { addr, ty } => {
+                let addr = self.gen_expression(*addr);
+                assert!(
+                    addr.ty.int_repr().is_some(),
+                    "Loading from {:?}, which isn't an address",
+                    addr.ty
+                );
+                let addr = addr.into_gp();
+                if ty.is_scalar() {
+                    let value = self.load_mem(ty, addr.reg(), 0);
+                    self.free(addr);
+                    value
+                } else {
+                    Value::gp(ty, addr)
+                }
+            }
+            Expression::VaStart => self.gen_va_start(),
+            Expression::VaArg { list, ty } => {
+                let list = self.gen_expression(*list);
+                assert!(
+                    matches!(list.ty, Type::VaList | Type::Ptr),
+                    "Reading a variadic argument from {:?}",
+                    list.ty
+                );
+                let list = list.into_gp();
+                let value = self.gen_va_arg(list.reg(), ty);
+                self.free(list);
+                value
+            }
+            Expression::VaEnd { list } => {
+                let list = self.gen_expression(*list);
+                assert!(
+                    matches!(list.ty, Type::VaList | Type::Ptr),
+                    "Ending {:?}, which isn't a va_list",
+                    list.ty
+                );
+                let reg = list.into_gp();
+                self.opt
+                    .accept(Instr::SetConstant(reg.reg(), Constant::Value(0)));
+                Value::gp(Type::I64, reg)
+            }
+        }
+    }
+
+    /// Converts between integer and pointer types. Values are truncated to the width of `to`
+    /// and extended back according to its signedness, unless every value of the source type is
+    /// already one of `to`
+    fn gen_cast(&mut self, value: Value, to: Type) -> Value {
+        let (from_width, from_signed) = value
+            .ty
+            .int_repr()
+            .unwrap_or_else(|| panic!("Casting from {:?}, which isn't an integer", value.ty));
+        let (to_width, to_signed) = to
+            .int_repr()
+            .unwrap_or_else(|| panic!("Casting to {to:?}, which isn't an integer"));
+        let contained = if from_width == to_width {
+            from_signed == to_signed
+        } else {
+            from_width < to_width && (to_signed || !from_signed)
+        };
+        let reg = value.into_gp();
+        if !contained {
+            self.canonicalize(reg.reg(), &to);
+        }
+        Value::gp(to, reg)
+    }
+
+    /// Converts the unsigned 64 bit integer in `orig` into a float. Values from 2^63 up, which
+    /// `cvtsi2sd` would take as negative, are halved before converting and the result doubled,
+    /// keeping their low bit so that they still round correctly. Clobbers `orig`
+    fn gen_u64_to_float(&mut self, size: FloatSize, dest: XmmRegister, orig: Register) {
+        // cmp orig, 0 ; jl large
+        // cvtsi2sd dest, orig ; jmp end
+        // large: mov tmp, 1 ; and tmp, orig ; shr orig, 1 ; or orig, tmp
+        // cvtsi2sd dest, orig ; addsd dest, dest
+        // end:
+        let large = self.new_label();
+        let end = self.new_label();
+        self.opt
+            .accept(Instr::CompareConstant { lhs: orig, val: 0 });
+        self.opt.accept(Instr::JumpIf(Condition::Less, large));
+        self.opt.accept(Instr::IntToFloat { size, dest, orig });
+        self.opt.accept(Instr::Jump(end));
+        self.opt.label(large);
+        let tmp = self.registers.allocate_any();
+        self.opt
+            .accept(Instr::SetConstant(tmp.reg(), Constant::Value(1)));
+        self.opt.accept(Instr::And {
+            dest: tmp.reg(),
+            orig,
+        });
+        self.opt.accept(Instr::ShiftRight {
+            dest: orig,
+            amount: 1,
+        });
+        self.opt.accept(Instr::Or {
+            dest: orig,
+            orig: tmp.reg(),
+        });
+        self.free(tmp);
+        self.opt.accept(Instr::IntToFloat { size, dest, orig });
+        self.opt.accept(Instr::FloatOp {
+            op: FloatOp::Add,
+            size,
+            dest,
+            orig: dest,
+        });
+        self.opt.label(end);
+    }
+
+    /// Truncates the float of type `ty` in `orig` into an unsigned 64 bit integer. Values from
+    /// 2^63 up, out of the range of `cvttsd2si`, have 2^63 subtracted before converting and
+    /// added back after. Clobbers `orig`
+    fn gen_float_to_u64(&mut self, ty: Type, dest: Register, orig: XmmRegister) {
+        // ucomisd orig, 2^63 ; jae large
+        // cvttsd2si dest, orig ; jmp end
+        // large: subsd orig, 2^63 ; cvttsd2si dest, orig
+        // mov tmp, 1 << 63 ; or dest, tmp
+        // end:
+        let size = float_size(&ty);
+        let limit = self
+            .gen_expression(Expression::FloatConst {
+                val: 2f64.powi(63),
+                ty,
+            })
+            .into_xmm();
+        let large = self.new_label();
+        let end = self.new_label();
+        self.opt.accept(Instr::FloatCompare {
+            size,
+            lhs: orig,
+            rhs: limit.reg(),
+        });
+        self.opt
+            .accept(Instr::JumpIf(Condition::AboveOrEqual, large));
+        self.opt.accept(Instr::FloatToInt { size, dest, orig });
+        self.opt.accept(Instr::Jump(end));
+        self.opt.label(large);
+        self.opt.accept(Instr::FloatOp {
+            op: FloatOp::Sub,
+            size,
+            dest: orig,
+            orig: limit.reg(),
+        });
+        self.free_xmm(limit);
+        self.opt.accept(Instr::FloatToInt { size, dest, orig });
+        let tmp = self.registers.allocate_any();
+        self.opt
+            .accept(Instr::SetConstant(tmp.reg(), Constant::Value(1 << 63)));
+        self.opt.accept(Instr::Or {
+            dest,
+            orig: tmp.reg(),
+        });
+        self.free(tmp);
+        self.opt.label(end);
+    }
+
+    /// Sign or zero extends the low bits of `reg` if `ty` is narrower than 64 bits
+    fn canonicalize(&mut self, reg: Register, ty: &Type) {
+        let Some((size, signed)) = narrow_int(ty) else {
+            return;
+        };
+        self.opt.accept(if signed {
+            Instr::SignExtend {
+                size,
+                dest: reg,
+                orig: reg,
+            }
+        } else {
+            Instr::ZeroExtend {
+                size,
+                dest: reg,
+                orig: reg,
+            }
+        });
+    }
+
+    /// Division (or multiplication) of `lhs` by `rhs`, returning the result in `lhs`.
+    /// `idiv`, `div` & `mul` work on rdx:rax, so any other live values in those are saved
+    /// around them
+    fn gen_wide(
+        &mut self,
+        lhs: RegAllocation,
+        rhs: RegAllocation,
+        op: WideOp<LabelId<SE>>,
+    ) -> RegAllocation {
+        let rax = RegisterRequest(0);
+        let rdx = RegisterRequest(2);
+        let rhs = if rhs == rax || rhs == rdx {
+            // The operand can't live in rdx:rax
+            let tmp_rax = self
+                .registers
+                .is_available(rax)
+                .then(|| self.registers.allocate(rax));
+            let tmp_rdx = self
+                .registers
+                .is_available(rdx)
+                .then(|| self.registers.allocate(rdx));
+            let new = self.registers.allocate_any();
+            tmp_rax.into_iter().for_each(|r| self.registers.free(r));
+            tmp_rdx.into_iter().for_each(|r| self.registers.free(r));
+            self.opt.accept(Instr::MoveRegs {
+                dest: new.reg(),
+                orig: rhs.reg(),
+            });
+            self.free(rhs);
+            new
+        } else {
+            rhs
+        };
+        let save_rax = lhs != rax && !self.registers.is_available(rax);
+        let save_rdx = lhs != rdx && !self.registers.is_available(rdx);
+        if save_rax {
+            self.push(Register(rax.0));
+        }
+        if save_rdx {
+            self.push(Register(rdx.0));
+        }
+        if lhs != rax {
+            self.opt.accept(Instr::MoveRegs {
+                dest: Register(rax.0),
+                orig: lhs.reg(),
+            });
+        }
+        match op {
+            WideOp::Div { signed: true } | WideOp::Rem { signed: true } => {
+                self.opt.accept(Instr::SignExtendRax);
+                self.opt.accept(Instr::SignedDiv(rhs.reg()));
+            }
+            WideOp::Div { signed: false } | WideOp::Rem { signed: false } => {
+                self.opt
+                    .accept(Instr::SetConstant(Register(rdx.0), Constant::Value(0)));
+                self.opt.accept(Instr::UnsignedDiv(rhs.reg()));
+            }
+            WideOp::CheckedUnsignedMul { overflow } => {
+                self.opt.accept(Instr::UnsignedMul(rhs.reg()));
+                self.opt.accept(Instr::JumpIf(Condition::Below, overflow));
+            }
+        }
+        let result = Register(if matches!(op, WideOp::Rem { .. }) {
+            rdx.0
+        } else {
+            rax.0
+        });
+        if lhs.reg() != result {
+            self.opt.accept(Instr::MoveRegs {
+                dest: lhs.reg(),
+                orig: result,
+            });
+        }
+        if save_rdx {
+            self.pop(Register(rdx.0));
+        }
+        if save_rax {
+            self.pop(Register(rax.0));
+        }
+        self.free(rhs);
+        lhs
+    }
+
+    /// The landing pad of the current function for failed checks of kind `trap`
+    fn trap_label(&mut self, trap: Trap) -> LabelId<SE> {
+        if let Some((_, label)) = self.fn_traps.iter().find(|(t, _)| *t == trap) {
+            return *label;
+        }
+        let label = self.new_label();
+        self.fn_traps.push((trap, label));
+        label
+    }
+
+    /// Traps if `reg` doesn't hold a value of the narrow integer type `ty`
+    fn check_fits(&mut self, reg: Register, ty: &Type) {
+        let tmp = self.registers.allocate_any();
+        self.opt.accept(Instr::MoveRegs {
+            dest: tmp.reg(),
+            orig: reg,
+        });
+        self.canonicalize(tmp.reg(), ty);
+        self.opt.accept(Instr::Compare {
+            lhs: tmp.reg(),
+            rhs: reg,
+        });
+        let trap = self.trap_label(Trap::Overflow);
+        self.opt.accept(Instr::JumpIf(Condition::NotEqual, trap));
+        self.free(tmp);
+    }
+
+    /// Traps on divisions by zero, and on `i64::MIN / -1`, whose quotient doesn't fit
+    fn check_divisor(&mut self, lhs: Register, rhs: Register, ty: &Type) {
+        self.opt.accept(Instr::CompareConstant { lhs: rhs, val: 0 });
+        let trap = self.trap_label(Trap::DivisionByZero);
+        self.opt.accept(Instr::JumpIf(Condition::Equal, trap));
+        if *ty == Type::I64 {
+            let skip = self.new_label();
+            self.opt
+                .accept(Instr::CompareConstant { lhs: rhs, val: -1 });
+            self.opt.accept(Instr::JumpIf(Condition::NotEqual, skip));
+            let min = self.registers.allocate_any();
+            self.opt.accept(Instr::SetConstant(
+                min.reg(),
+                Constant::Value(i64::MIN as u64),
+            ));
+            self.opt.accept(Instr::Compare {
+                lhs,
+                rhs: min.reg(),
+            });
+            let trap = self.trap_label(Trap::Overflow);
+            self.opt.accept(Instr::JumpIf(Condition::Equal, trap));
+            self.free(min);
+            self.opt.label(skip);
+        }
+    }
+
+    /// Emits the landing pads of the failed checks of the current function, which hand a
+    /// message naming it to the abort stub
+    fn gen_trap_pads(&mut self) {
+        let current = self.current_fn.expect("Not inside a function");
+        let name = self.st.get(current).lexeme.clone();
+        for (trap, label) in std::mem::take(&mut self.fn_traps) {
+            // The canary checks are skipped when the function turned out to need none
+            if trap == Trap::StackSmashing && !self.fn_buffers {
+                continue;
+            }
+            let message = format!("{} in {name}\n", trap.message());
+            let addr = self.add_data(message.as_bytes(), 1);
+            self.opt.label(label);
+            self.gen_abort(
+                Constant::Tbd(LabelId::DataAddr(addr)),
+                message.len() as u64,
+                trap.exit_code(),
+            );
+        }
+    }
+
+    /// Performs `syscall` with `args`, returning rax holding its result
+    fn gen_syscall(&mut self, syscall: Syscall<SE>, args: Vec<Expression<SE>>) -> RegAllocation {
+        let (syscall, signature) = match syscall {
+            Syscall::Name(name) => {
+                let signature = syscall_table::by_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown syscall {name}"));
+                let nr = Expression::IntConst { val: signature.nr };
+                (nr, Some(signature))
+            }
+            Syscall::Number(expr) => {
+                let signature = match *expr {
+                    Expression::IntConst { val } => syscall_table::by_nr(val),
+                    _ => None,
+                };
+                (*expr, signature)
+            }
+        };
+        assert!(
+            args.len() <= ARG_REGISTERS.len(),
+            "Syscalls take at most {} arguments",
+            ARG_REGISTERS.len()
+        );
+        if let Some(signature) = signature {
+            assert_eq!(
+                args.len(),
+                signature.args.len(),
+                "Wrong number of arguments for syscall {}",
+                signature.name
+            );
+        }
+        let mut reg = self.gen_expression(syscall).into_gp();
+        if reg != SYSCALL_REG {
+            let old_reg = reg;
+            reg = self.registers.allocate(SYSCALL_REG);
+            self.opt.accept(Instr::MoveRegs {
+                dest: reg.reg(),
+                orig: old_reg.reg(),
+            });
+            self.free(old_reg);
+        }
+        let regs = args
+            .into_iter()
+            .enumerate()
+            .map(|(i, expr)| {
+                let expected = ARG_REGISTERS[i];
+                let value = self.gen_expression(expr);
+                if let Some(signature) = signature {
+                    assert!(
+                        signature.args[i].accepts(&value.ty),
+                        "Argument {i} of syscall {} can't be {:?}",
+                        signature.name,
+                        value.ty
+                    );
+                }
+                let gotten = value.into_gp();
+                if gotten == expected {
+                    gotten
+                } else {
+                    let expected = self.registers.allocate(expected);
+                    self.opt.accept(Instr::MoveRegs {
+                        dest: expected.reg(),
+                        orig: gotten.reg(),
+                    });
+                    self.free(gotten);
+                    expected
+                }
+            })
+            .collect::<Vec<_>>();
+        self.opt.accept(Instr::Syscall);
+        for reg in regs {
+            self.free(reg);
+        }
+        reg
+    }
+
+    /// Jumps to `label` if the result of a syscall held in `result` is an error, in
+    /// `-MAX_ERRNO..=-1`
+    fn jump_if_errno(&mut self, result: Register, label: LabelId<SE>) {
+        // cmp <result>, -MAX_ERRNO - 1
+        // ja <label>
+        self.opt.accept(Instr::CompareConstant {
+            lhs: result,
+            val: -(syscall_table::MAX_ERRNO as i32) - 1,
+        });
+        self.opt.accept(Instr::JumpIf(Condition::Above, label));
+    }
+
+    /// Negates the failed syscall result in `result` into its error code
+    fn errno_of(&mut self, result: RegAllocation) -> RegAllocation {
+        // mov <errno>, 0
+        // sub <errno>, <result>
+        let errno = self.registers.allocate_any();
+        self.opt
+            .accept(Instr::SetConstant(errno.reg(), Constant::Value(0)));
+        self.opt.accept(Instr::IntOp {
+            op: IntOp::Sub,
+            dest: errno.reg(),
+            orig: result.reg(),
+        });
+        self.free(result);
+        errno
+    }
+
+    /// Performs `syscall` with `args`, splitting its result into a `(value, errno)` tuple. Every
+    /// register in use is saved on the stack around the syscall, which clobbers rcx and r11 too
+    fn gen_try_syscall_expr(&mut self, syscall: Syscall<SE>, args: Vec<Expression<SE>>) -> Value {
+        let offset = self.alloc_buffer(2 * SLOT_SIZE);
+        let suspended = self.registers.suspend();
+        for reg in suspended.gp.iter().copied() {
+            self.push(reg);
+        }
+        for reg in suspended.xmm.iter().copied() {
+            self.push_xmm(reg);
+        }
+        let result = self.gen_syscall(syscall, args);
+        let failed = self.new_label();
+        let end = self.new_label();
+        self.jump_if_errno(result.reg(), failed);
+        // Success: (result, 0)
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + Self::elem_offset(0),
+            orig: result.reg(),
+        });
+        self.opt
+            .accept(Instr::SetConstant(result.reg(), Constant::Value(0)));
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + Self::elem_offset(1),
+            orig: result.reg(),
+        });
+        self.opt.accept(Instr::Jump(end));
+        // Failure: (-1, -result)
+        self.opt.label(failed);
+        let errno = self.errno_of(result);
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + Self::elem_offset(1),
+            orig: errno.reg(),
+        });
+        self.opt
+            .accept(Instr::SetConstant(errno.reg(), Constant::Value(u64::MAX)));
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + Self::elem_offset(0),
+            orig: errno.reg(),
+        });
+        self.opt.label(end);
+        self.free(errno);
+
+        self.registers.resume(&suspended);
+        let reg = self.registers.allocate_any();
+        self.opt.accept(Instr::LoadAddress {
+            dest: reg.reg(),
+            base: STACK_FRAME_POINTER,
+            offset,
+        });
+        for reg in suspended.xmm.iter().rev().copied() {
+            self.pop_xmm(reg);
+        }
+        for reg in suspended.gp.iter().rev().copied() {
+            self.pop(reg);
+        }
+        Value::gp(Type::Tuple(vec![Type::I64, Type::I64]), reg)
+    }
+
+    /// Performs `syscall` with `args`, assigning its result to `result` on success and running
+    /// `on_error` on failure
+    fn gen_try_syscall(
+        &mut self,
+        syscall: Syscall<SE>,
+        args: Vec<Expression<SE>>,
+        result: Option<String>,
+        on_error: ErrorArm<SE>,
+    ) {
+        let value = self.gen_syscall(syscall, args);
+        let failed = self.new_label();
+        let end = self.new_label();
+        self.jump_if_errno(value.reg(), failed);
+        if let Some(name) = result {
+            let (ty, offset) = self.resolve(&name);
+            // Such as a file descriptor, a count or the address of a mapping
+            assert!(
+                matches!(ty.int_repr(), Some((64, _))),
+                "Assigning a syscall result to {name}, of type {ty:?}"
+            );
+            self.opt.accept(Instr::Store {
+                base: STACK_FRAME_POINTER,
+                offset,
+                orig: value.reg(),
+            });
+        }
+        self.opt.accept(Instr::Jump(end));
+        self.opt.label(failed);
+        self.enter_scope(on_error.st);
+        let errno = self.errno_of(value);
+        match on_error.errno {
+            Some(name) => {
+                let (ty, offset) = self.resolve(&name);
+                assert_eq!(ty, Type::I64, "Mismatched type binding {name}");
+                self.store_slot(Value::gp(Type::I64, errno), offset);
+            }
+            None => self.free(errno),
+        }
+        for statement in on_error.body {
+            self.gen_statement(statement);
+        }
+        self.leave_scope();
+        self.opt.label(end);
+    }
+
+    fn gen_panic(&mut self, message: &str, location: Option<Location>) {
+        let current = self.current_fn.expect("Not inside a function");
+        let name = &self.st.get(current).lexeme;
+        let message = match location {
+            Some(Location { file, line, column }) => {
+                format!("panicked in {name} at {file}:{line}:{column}: {message}\n")
+            }
+            None => format!("panicked in {name}: {message}\n"),
+        };
+        let addr = self.add_data(message.as_bytes(), 1);
+        self.gen_abort(
+            Constant::Tbd(LabelId::DataAddr(addr)),
+            message.len() as u64,
+            PANIC_EXIT_CODE,
+        );
+    }
+
+    /// Passes the initial process stack, laid out as argc followed by the NULL terminated argv
+    /// & envp arrays, to `main` as the argc, argv & envp arguments it declares
+    fn gen_main_args(&mut self, main: SE) {
+        let entry = self
+            .st
+            .get(main)
+            .kind
+            .unwrap_function()
+            .expect("main isn't a function");
+        // Its result is the exit status, which must be left in rax
+        assert!(
+            entry.ret.int_repr().is_some() && entry.ret != Type::Ptr,
+            "main returns {:?} instead of an integer",
+            entry.ret
+        );
+        let params = [Type::I64, Type::Ptr, Type::Ptr];
+        assert!(
+            params.starts_with(&entry.args) && !entry.variadic,
+            "main takes {:?} instead of a prefix of (argc: i64, argv: ptr, envp: ptr)",
+            entry.args
+        );
+        // mov rdi, [rsp] ; argc
+        // lea rsi, [rsp + 8] ; argv
+        // mov rdx, 8
+        // imul rdx, rdi
+        // add rdx, rsi
+        // add rdx, 8 ; envp, past the NULL ending argv
+        let argc = Register(CALL_ARG_REGISTERS[0].0);
+        let argv = Register(CALL_ARG_REGISTERS[1].0);
+        let envp = Register(CALL_ARG_REGISTERS[2].0);
+        self.opt.accept(Instr::Load {
+            dest: argc,
+            base: STACK_POINTER,
+            offset: 0,
+        });
+        self.opt.accept(Instr::LoadAddress {
+            dest: argv,
+            base: STACK_POINTER,
+            offset: 8,
+        });
+        self.opt
+            .accept(Instr::SetConstant(envp, Constant::Value(8)));
+        self.opt.accept(Instr::IntOp {
+            op: IntOp::Mul,
+            dest: envp,
+            orig: argc,
+        });
+        self.opt.accept(Instr::IntOp {
+            op: IntOp::Add,
+            dest: envp,
+            orig: argv,
+        });
+        self.opt.accept(Instr::AddConstant { dest: envp, val: 8 });
+    }
+
+    /// Jumps to the abort stub, which never returns, so the state of registers & the stack
+    /// doesn't matter
+    fn gen_abort(&mut self, message: Constant<LabelId<SE>>, len: u64, status: u64) {
+        self.opt.accept(Instr::SetConstant(Register(6), message));
+        self.opt
+            .accept(Instr::SetConstant(Register(2), Constant::Value(len)));
+        self.opt
+            .accept(Instr::SetConstant(Register(7), Constant::Value(status)));
+        let stub = self.use_routine(Routine::Abort);
+        self.opt.accept(Instr::Jump(stub));
+    }
+
+    fn gen_float_cmp(
+        &mut self,
+        op: CmpOp,
+        size: FloatSize,
+        lhs: XmmRegister,
+        rhs: XmmRegister,
+    ) -> RegAllocation {
+        // Unordered comparisons set ZF, PF & CF, so only `above` conditions are false for NaNs
+        let res = self.registers.allocate_any();
+        let (lhs, rhs, cond) = match op {
+            CmpOp::Gt => (lhs, rhs, Condition::Above),
+            CmpOp::Ge => (lhs, rhs, Condition::AboveOrEqual),
+            CmpOp::Lt => (rhs, lhs, Condition::Above),
+            CmpOp::Le => (rhs, lhs, Condition::AboveOrEqual),
+            CmpOp::Eq => (lhs, rhs, Condition::Equal),
+            CmpOp::Ne => (lhs, rhs, Condition::NotEqual),
+        };
+        self.opt.accept(Instr::FloatCompare { size, lhs, rhs });
+        self.opt.accept(Instr::SetCondition(cond, res.reg()));
+        if matches!(op, CmpOp::Eq | CmpOp::Ne) {
+            let parity = self.registers.allocate_any();
+            if op == CmpOp::Eq {
+                self.opt
+                    .accept(Instr::SetCondition(Condition::NotParity, parity.reg()));
+                self.opt.accept(Instr::And {
+                    dest: res.reg(),
+                    orig: parity.reg(),
+                });
+            } else {
+                self.opt
+                    .accept(Instr::SetCondition(Condition::Parity, parity.reg()));
+                self.opt.accept(Instr::Or {
+                    dest: res.reg(),
+                    orig: parity.reg(),
+                });
+            }
+            self.free(parity);
+        }
+        res
+    }
+
+    /// Dispatches on the tag of an enum value, copying the fields of the matched variant into
+    /// the bindings of its arm
+    fn gen_match(
+        &mut self,
+        scrutinee: Expression<SE>,
+        arms: Vec<MatchArm<SE>>,
+        default: Option<Vec<Statement<SE>>>,
+    ) {
+        let value = self.gen_expression(scrutinee);
+        let Type::Enum(name) = &value.ty else {
+            panic!("Matching on {:?}, which isn't an enum", value.ty)
+        };
+        let def = self.resolve_enum(name);
+        let arms = arms
+            .into_iter()
+            .map(|arm| {
+                let (tag, variant) = def
+                    .variant(&arm.variant)
+                    .unwrap_or_else(|| panic!("{name} has no variant {}", arm.variant));
+                (tag, variant.fields.clone(), arm)
+            })
+            .collect::<Vec<_>>();
+        // The address is kept in the frame, as the arms need every register
+        let addr = value.into_gp();
+        let addr_offset = self.alloc_temp(SLOT_SIZE);
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: addr_offset,
+            orig: addr.reg(),
+        });
+        self.opt.accept(Instr::Load {
+            dest: addr.reg(),
+            base: addr.reg(),
+            offset: 0,
+        });
+        let labels = arms
+            .iter()
+            .map(|(tag, _, _)| {
+                let label = self.new_label();
+                self.opt.accept(Instr::CompareConstant {
+                    lhs: addr.reg(),
+                    val: *tag as i32,
+                });
+                self.opt.accept(Instr::JumpIf(Condition::Equal, label));
+                label
+            })
+            .collect::<Vec<_>>();
+        self.free(addr);
+
+        let end = self.new_label();
+        for statement in default.into_iter().flatten() {
+            self.gen_statement(statement);
+        }
+        self.opt.accept(Instr::Jump(end));
+        for ((_, fields, arm), label) in arms.into_iter().zip(labels) {
+            self.opt.label(label);
+            self.enter_scope(arm.st);
+            assert!(arm.bindings.len() <= fields.len(), "Too many bindings");
+            for (i, (binding, field_ty)) in arm.bindings.into_iter().zip(fields).enumerate() {
+                let Some(binding) = binding else { continue };
+                let (ty, offset) = self.resolve(&binding);
+                assert_eq!(ty, field_ty, "Mismatched type binding {binding}");
+                let addr = self.registers.allocate_any();
+                self.opt.accept(Instr::Load {
+                    dest: addr.reg(),
+                    base: STACK_FRAME_POINTER,
+                    offset: addr_offset,
+                });
+                let field = self.load_mem(ty, addr.reg(), Self::field_offset(i));
+                self.free(addr);
+                self.store_slot(field, offset);
+            }
+            for statement in arm.body {
+                self.gen_statement(statement);
+            }
+            self.leave_scope();
+            self.opt.accept(Instr::Jump(end));
+        }
+        self.opt.label(end);
+    }
+
+    /// Argument types (when known), return type and variadicity of the function called by
+    /// `target`
+    fn call_signature(
+        &self,
+        target: &CallTarget<SE>,
+        nargs: usize,
+    ) -> (Option<Vec<Type>>, Type, bool) {
+        match target {
+            CallTarget::Direct(func) => {
+                let entry = self
+                    .st
+                    .get(*func)
+                    .kind
+                    .unwrap_function()
+                    .expect("Called symbol isn't a function");
+                if entry.variadic {
+                    assert!(nargs >= entry.args.len(), "Missing arguments");
+                } else {
+                    assert_eq!(nargs, entry.args.len(), "Wrong number of arguments");
+                }
+                (Some(entry.args.clone()), entry.ret.clone(), entry.variadic)
+            }
+            CallTarget::Indirect { ret, variadic, .. } => (None, ret.clone(), *variadic),
+            CallTarget::Runtime(routine) => {
+                let (args, ret) = routine.signature();
+                assert_eq!(nargs, args.len(), "Wrong number of arguments");
+                (Some(args), ret, false)
+            }
+        }
+    }
+
+    /// Evaluates the arguments of a call into their ABI registers. They are staged on the stack
+    /// so that evaluating one can't clobber the registers of the previous ones.
+    fn stage_call(
+        &mut self,
+        target: CallTarget<SE>,
+        args: Vec<Expression<SE>>,
+        expected_types: Option<Vec<Type>>,
+        in_memory: bool,
+        variadic: bool,
+    ) -> StagedCall<SE> {
+        let label = match target {
+            CallTarget::Direct(func) => Some(self.fn_ref(func)),
+            CallTarget::Runtime(routine) => Some(self.use_routine(routine)),
+            CallTarget::Indirect { addr, .. } => {
+                let addr = self.gen_expression(addr);
+                assert!(!addr.ty.is_float(), "Called address isn't a pointer");
+                let addr = addr.into_gp();
+                self.push(addr.reg());
+                self.free(addr);
+                None
+            }
+        };
+        let mut evaluated_types = Vec::with_capacity(args.len());
+        let mut arg_types = Vec::with_capacity(args.len());
+        for (i, expr) in args.into_iter().enumerate() {
+            let mut value = self.gen_expression(expr);
+            match expected_types.as_ref().and_then(|expected| expected.get(i)) {
+                Some(expected) => assert_eq!(&value.ty, expected, "Mismatched argument type"),
+                // Variadic arguments are promoted like in C
+                None if value.ty == Type::F32 => {
+                    let reg = value.into_xmm();
+                    self.opt.accept(Instr::FloatResize {
+                        to: FloatSize::Double,
+                        dest: reg.reg(),
+                        orig: reg.reg(),
+                    });
+                    value = Value::xmm(Type::F64, reg);
+                }
+                None => (),
+            }
+            evaluated_types.push(value.ty.clone());
+            if value.ty == Type::VaList {
+                // Passed by address
+                value.ty = Type::Ptr;
+            }
+            assert!(
+                value.ty.is_scalar(),
+                "Passing {:?} by value isn't supported",
+                value.ty
+            );
+            arg_types.push(value.ty);
+            match value.reg {
+                ValueReg::Gp(reg) => {
+                    self.push(reg.reg());
+                    self.free(reg);
+                }
+                ValueReg::Xmm(reg) => {
+                    self.push_xmm(reg.reg());
+                    self.free_xmm(reg);
+                }
+            }
+        }
+        if in_memory {
+            arg_types.insert(0, Type::Ptr);
+        }
+        let mut locations = arg_locations(&arg_types);
+        let ret_ptr = in_memory.then(|| match locations.remove(0) {
+            ArgLocation::Gp(req) => self.registers.allocate(req),
+            ArgLocation::Xmm(_) => unreachable!(),
+        });
+        let args = locations
+            .into_iter()
+            .rev()
+            .map(|loc| match loc {
+                ArgLocation::Gp(req) => {
+                    let reg = self.registers.allocate(req);
+                    self.pop(reg.reg());
+                    ValueReg::Gp(reg)
+                }
+                ArgLocation::Xmm(req) => {
+                    let reg = self.registers.allocate_xmm(req);
+                    self.pop_xmm(reg.reg());
+                    ValueReg::Xmm(reg)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The address was pushed before the arguments, so it's left at the top of the stack
+        let addr = label.is_none().then(|| {
+            let addr = self.registers.allocate(INDIRECT_CALL_REG);
+            self.pop(addr.reg());
+            addr
+        });
+        // mov rax, <float registers used> // an upper bound is enough for variadic functions
+        let float_count = variadic.then(|| {
+            let count = args
+                .iter()
+                .filter(|reg| matches!(reg, ValueReg::Xmm(_)))
+                .count();
+            let reg = self.registers.allocate(RETURN_REG);
+            self.opt
+                .accept(Instr::SetConstant(reg.reg(), Constant::Value(count as u64)));
+            reg
+        });
+        StagedCall {
+            label,
+            addr,
+            args,
+            ret_ptr,
+            float_count,
+            arg_types: evaluated_types,
+        }
+    }
+
+    fn free_staged_call(&mut self, call: StagedCall<SE>) {
+        for reg in call.args {
+            self.free_value_reg(reg);
+        }
+        if let Some(addr) = call.addr {
+            self.free(addr);
+        }
+        if let Some(ret_ptr) = call.ret_ptr {
+            self.free(ret_ptr);
+        }
+        if let Some(float_count) = call.float_count {
+            self.free(float_count);
+        }
+    }
+
+    /// Calls `func` following the SysV ABI. Every register in use is saved on the stack around
+    /// the call.
+    fn gen_call(&mut self, target: CallTarget<SE>, args: Vec<Expression<SE>>) -> Value {
+        let (expected_types, ret, variadic) = self.call_signature(&target, args.len());
+        let in_memory = self.returns_in_memory(&ret);
+
+        let suspended = self.registers.suspend();
+        for reg in suspended.gp.iter().copied() {
+            self.push(reg);
+        }
+        for reg in suspended.xmm.iter().copied() {
+            self.push_xmm(reg);
+        }
+
+        let call = self.stage_call(target, args, expected_types, in_memory, variadic);
+        let ret_offset = self.gen_staged_call(call, &ret);
+        self.registers.resume(&suspended);
+        let result = self.call_result(ret, ret_offset);
+        for reg in suspended.xmm.iter().rev().copied() {
+            self.pop_xmm(reg);
+        }
+        for reg in suspended.gp.iter().rev().copied() {
+            self.pop(reg);
+        }
+        result
+    }
+
+    /// Calls the function of a staged call. Aggregates are returned into a temporary of the
+    /// caller, whose offset is returned, either directly by the callee through a hidden pointer
+    /// argument or copied from the return registers after the call
+    fn gen_staged_call(&mut self, call: StagedCall<SE>, ret: &Type) -> Option<i32> {
+        let ret_offset = (!ret.is_scalar()).then(|| self.alloc_buffer(self.type_size(ret)));
+        if let Some(ret_ptr) = &call.ret_ptr {
+            self.opt.accept(Instr::LoadAddress {
+                dest: ret_ptr.reg(),
+                base: STACK_FRAME_POINTER,
+                offset: ret_offset.unwrap(),
+            });
+        }
+
+        let padding = !self.stack_depth.is_multiple_of(16);
+        if padding {
+            self.opt.accept(Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            });
+        }
+        match (&call.addr, call.label) {
+            (Some(addr), _) => self.opt.accept(Instr::CallIndirect(addr.reg())),
+            (None, label) => self.opt.accept(Instr::Call(label.unwrap())),
+        }
+        if padding {
+            self.opt.accept(Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            });
+        }
+        let in_memory = call.ret_ptr.is_some();
+        self.free_staged_call(call);
+        if let (Some(offset), false) = (ret_offset, in_memory) {
+            for (i, reg) in self.return_registers(ret).into_iter().enumerate() {
+                let offset = offset + Self::elem_offset(i);
+                match reg {
+                    ArgLocation::Gp(req) => self.opt.accept(Instr::Store {
+                        base: STACK_FRAME_POINTER,
+                        offset,
+                        orig: Register(req.0),
+                    }),
+                    ArgLocation::Xmm(req) => self.opt.accept(Instr::StoreXmm {
+                        size: FloatSize::Double,
+                        base: STACK_FRAME_POINTER,
+                        offset,
+                        orig: XmmRegister(req.0),
+                    }),
+                }
+            }
+        }
+        ret_offset
+    }
+
+    /// The value returned by a call, of type `ret`, as left by [`Self::gen_staged_call`]
+    fn call_result(&mut self, ret: Type, ret_offset: Option<i32>) -> Value {
+        if let Some(offset) = ret_offset {
+            let reg = self.registers.allocate_any();
+            self.opt.accept(Instr::LoadAddress {
+                dest: reg.reg(),
+                base: STACK_FRAME_POINTER,
+                offset,
+            });
+            Value::gp(ret, reg)
+        } else if ret.is_float() {
+            let reg = if self.registers.is_available_xmm(FLOAT_RETURN_REG) {
+                self.registers.allocate_xmm(FLOAT_RETURN_REG)
+            } else {
+                let reg = self.registers.allocate_any_xmm();
+                self.opt.accept(Instr::MoveXmm {
+                    dest: reg.reg(),
+                    orig: XmmRegister(FLOAT_RETURN_REG.0),
+                });
                 reg
+            };
+            Value::xmm(ret, reg)
+        } else {
+            let reg = if self.registers.is_available(RETURN_REG) {
+                self.registers.allocate(RETURN_REG)
+            } else {
+                let reg = self.registers.allocate_any();
+                self.opt.accept(Instr::MoveRegs {
+                    dest: reg.reg(),
+                    orig: Register(RETURN_REG.0),
+                });
+                reg
+            };
+            // The upper bits of narrow integers are unspecified by the ABI
+            self.canonicalize(reg.reg(), &ret);
+            Value::gp(ret, reg)
+        }
+    }
+
+    /// Returns `value` from the current function, through the return registers or its hidden
+    /// return pointer
+    fn gen_return(&mut self, value: Option<Value>) {
+        let ret = value.and_then(|value| {
+            assert_eq!(&value.ty, self.current_ret(), "Mismatched return type");
+            if value.ty.is_scalar() {
+                Some(value.reg)
+            } else {
+                self.gen_return_aggregate(value);
+                None
+            }
+        });
+        let ret = ret.map(|reg| match reg {
+            ValueReg::Gp(reg) if reg != RETURN_REG => {
+                let ret = self.registers.allocate(RETURN_REG);
+                self.opt.accept(Instr::MoveRegs {
+                    dest: ret.reg(),
+                    orig: reg.reg(),
+                });
+                self.free(reg);
+                ValueReg::Gp(ret)
+            }
+            ValueReg::Xmm(reg) if reg != FLOAT_RETURN_REG => {
+                let ret = self.registers.allocate_xmm(FLOAT_RETURN_REG);
+                self.opt.accept(Instr::MoveXmm {
+                    dest: ret.reg(),
+                    orig: reg.reg(),
+                });
+                self.free_xmm(reg);
+                ValueReg::Xmm(ret)
+            }
+            reg => reg,
+        });
+        self.function_exit_harness();
+        if let Some(reg) = ret {
+            self.free_value_reg(reg);
+        }
+    }
+
+    /// Whether a staged call can still be made once the frame of the current function is torn
+    /// down. Only calls passed nothing but scalars, which are copied into registers, qualify:
+    /// `va_list`s are passed by address, and the variadic arguments of a variadic function are
+    /// read through its frame
+    fn can_tail_call(&self, call: &StagedCall<SE>) -> bool {
+        !self.current_entry().variadic && call.arg_types.iter().all(Type::is_scalar)
+    }
+
+    /// Jumps to `target` after tearing down the frame of the current function, so that it
+    /// returns straight to our caller and recursion in tail position runs in constant stack.
+    /// Aggregates returned through memory reuse our own hidden return pointer. Calls that don't
+    /// qualify for [`Self::can_tail_call`] are made normally instead
+    fn gen_tail_call(&mut self, target: CallTarget<SE>, args: Vec<Expression<SE>>) {
+        let (expected_types, ret, variadic) = self.call_signature(&target, args.len());
+        assert_eq!(&ret, self.current_ret(), "Mismatched return type");
+        let in_memory = self.returns_in_memory(&ret);
+        let call = self.stage_call(target, args, expected_types, in_memory, variadic);
+        if !self.can_tail_call(&call) {
+            // Nothing in use needs saving around the call, as we return right after it
+            let ret_offset = self.gen_staged_call(call, &ret);
+            let value = self.call_result(ret, ret_offset);
+            self.gen_return(Some(value));
+            return;
+        }
+        if let Some(ret_ptr) = &call.ret_ptr {
+            self.opt.accept(Instr::Load {
+                dest: ret_ptr.reg(),
+                base: STACK_FRAME_POINTER,
+                offset: self.ret_ptr_offset(),
+            });
+        }
+        self.leave_frame();
+        match (&call.addr, call.label) {
+            (Some(addr), _) => self.opt.accept(Instr::JumpIndirect(addr.reg())),
+            (None, label) => self.opt.accept(Instr::Jump(label.unwrap())),
+        }
+        self.free_staged_call(call);
+    }
+
+    fn load_slot(&mut self, ty: Type, offset: i32) -> Value {
+        self.load_mem(ty, STACK_FRAME_POINTER, offset)
+    }
+
+    fn store_slot(&mut self, value: Value, offset: i32) {
+        self.store_mem(value, STACK_FRAME_POINTER, offset)
+    }
+
+    /// Loads a value of type `ty` from `[base + offset]`. Aggregates are loaded as their address
+    fn load_mem(&mut self, ty: Type, base: Register, offset: i32) -> Value {
+        if ty.is_float() {
+            let reg = self.registers.allocate_any_xmm();
+            self.opt.accept(Instr::LoadXmm {
+                size: float_size(&ty),
+                dest: reg.reg(),
+                base,
+                offset,
+            });
+            Value::xmm(ty, reg)
+        } else if let Some((size, signed)) = narrow_int(&ty) {
+            let reg = self.registers.allocate_any();
+            self.opt.accept(Instr::LoadNarrow {
+                size,
+                signed,
+                dest: reg.reg(),
+                base,
+                offset,
+            });
+            Value::gp(ty, reg)
+        } else if ty.is_scalar() {
+            let reg = self.registers.allocate_any();
+            self.opt.accept(Instr::Load {
+                dest: reg.reg(),
+                base,
+                offset,
+            });
+            Value::gp(ty, reg)
+        } else {
+            let reg = self.registers.allocate_any();
+            self.opt.accept(Instr::LoadAddress {
+                dest: reg.reg(),
+                base,
+                offset,
+            });
+            Value::gp(ty, reg)
+        }
+    }
+
+    /// Stores `value` into `[base + offset]`, copying the whole of aggregates
+    fn store_mem(&mut self, value: Value, base: Register, offset: i32) {
+        match value.reg {
+            ValueReg::Gp(reg) if !value.ty.is_scalar() => {
+                let size = self.type_size(&value.ty);
+                let tmp = self.registers.allocate_any();
+                for i in (0..size).step_by(8) {
+                    self.opt.accept(Instr::Load {
+                        dest: tmp.reg(),
+                        base: reg.reg(),
+                        offset: i as i32,
+                    });
+                    self.opt.accept(Instr::Store {
+                        base,
+                        offset: offset + i as i32,
+                        orig: tmp.reg(),
+                    });
+                }
+                self.free(tmp);
+                self.free(reg);
+            }
+            ValueReg::Gp(reg) => {
+                self.opt.accept(match narrow_int(&value.ty) {
+                    Some((size, _)) => Instr::StoreNarrow {
+                        size,
+                        base,
+                        offset,
+                        orig: reg.reg(),
+                    },
+                    None => Instr::Store {
+                        base,
+                        offset,
+                        orig: reg.reg(),
+                    },
+                });
+                self.free(reg);
+            }
+            ValueReg::Xmm(reg) => {
+                self.opt.accept(Instr::StoreXmm {
+                    size: float_size(&value.ty),
+                    base,
+                    offset,
+                    orig: reg.reg(),
+                });
+                self.free_xmm(reg);
+            }
+        }
+    }
+
+    /// Size in bytes of a value of type `ty` in memory
+    fn type_size(&self, ty: &Type) -> u64 {
+        match ty {
+            Type::Enum(name) => self.resolve_enum(name).size(),
+            Type::Tuple(types) => types.len() as u64 * SLOT_SIZE,
+            Type::VaList => VA_LIST_SIZE,
+            _ => SLOT_SIZE,
+        }
+    }
+
+    /// Whether values of type `ty` are returned through a hidden pointer argument rather than in
+    /// registers, which SysV does for aggregates bigger than 16 bytes
+    fn returns_in_memory(&self, ty: &Type) -> bool {
+        !ty.is_scalar() && self.type_size(ty) > 2 * SLOT_SIZE
+    }
+
+    /// Registers an aggregate of up to 16 bytes is returned in, one per 8 byte slot. Following
+    /// the SysV classification, slots holding only floats go in xmm0 & xmm1, and the rest in
+    /// rax & rdx
+    fn return_registers(&self, ty: &Type) -> Vec<ArgLocation> {
+        let float_slots = match ty {
+            Type::Tuple(types) => types.iter().map(Type::is_float).collect(),
+            Type::Enum(name) => {
+                let def = self.resolve_enum(name);
+                let slots = (def.size() / SLOT_SIZE) as usize;
+                // The tag is an integer, and a field slot is only a float if it is in every
+                // variant that has it
+                (0..slots)
+                    .map(|slot| {
+                        slot > 0
+                            && def
+                                .variants
+                                .iter()
+                                .filter_map(|v| v.fields.get(slot - 1))
+                                .all(Type::is_float)
+                    })
+                    .collect::<Vec<_>>()
+            }
+            _ => vec![ty.is_float()],
+        };
+        let mut gp = RETURN_REGISTERS.iter().copied();
+        let mut xmm = FLOAT_RETURN_REGISTERS.iter().copied();
+        float_slots
+            .into_iter()
+            .map(|float| {
+                if float {
+                    ArgLocation::Xmm(xmm.next().unwrap())
+                } else {
+                    ArgLocation::Gp(gp.next().unwrap())
+                }
+            })
+            .collect()
+    }
+
+    /// Offset of the `idx`th field of an enum value from its start, after the tag
+    const fn field_offset(idx: usize) -> i32 {
+        ((idx as u64 + 1) * SLOT_SIZE) as i32
+    }
+
+    /// Offset of the `idx`th element of a tuple from its start
+    const fn elem_offset(idx: usize) -> i32 {
+        (idx as u64 * SLOT_SIZE) as i32
+    }
+
+    /// Reserves `size` bytes of the frame until the end of the current statement, returning
+    /// their offset from the frame pointer
+    fn alloc_temp(&mut self, size: u64) -> i32 {
+        self.frame_top += size;
+        self.frame_size = self.frame_size.max(self.frame_top);
+        -(self.frame_top as i32)
+    }
+
+    /// Reserves a temporary aggregate like [`Self::alloc_temp`], which the stack protector
+    /// guards
+    fn alloc_buffer(&mut self, size: u64) -> i32 {
+        self.fn_buffers = true;
+        self.alloc_temp(size)
+    }
+
+    /// Label of the word holding the stack canary
+    fn canary_label(&mut self) -> LabelId<SE> {
+        let addr = match self.canary {
+            Some(addr) => addr,
+            None => {
+                let addr = self.add_rw_data(&[0; SLOT_SIZE as usize], 8);
+                self.canary = Some(addr);
+                addr
+            }
+        };
+        LabelId::RwDataAddr(addr)
+    }
+
+    /// Emits `block` behind a jump whose target, either into or over it, is only decided once
+    /// the current function ends by [`Self::resolve_optional_block`]
+    fn gen_optional_block(&mut self, block: impl FnOnce(&mut Self)) -> OptionalBlock<SE> {
+        let skip = self.new_label();
+        let start = self.new_label();
+        let end = self.new_label();
+        self.opt.accept(Instr::Jump(skip));
+        self.opt.label(start);
+        block(self);
+        self.opt.label(end);
+        (skip, start, end)
+    }
+
+    fn resolve_optional_block(&mut self, block: OptionalBlock<SE>, run: bool) {
+        let (skip, start, end) = block;
+        let target = if run { start } else { end };
+        let addr = self.opt.get_label(&target).unwrap();
+        self.opt.add_label(skip, addr);
+    }
+
+    /// Emits `block` handling the canary, which only runs if the current function turns out to
+    /// hold aggregates on the stack. That's only known once it ends, so the jump over it is
+    /// resolved by [`Self::resolve_canary_blocks`]
+    fn gen_canary_block(&mut self, block: impl FnOnce(&mut Self)) {
+        let block = self.gen_optional_block(block);
+        self.fn_canary_blocks.push(block);
+    }
+
+    fn resolve_canary_blocks(&mut self) {
+        if self.fn_buffers && self.options.stack_protector {
+            self.stack_protected = true;
+        }
+        for block in std::mem::take(&mut self.fn_canary_blocks) {
+            self.resolve_optional_block(block, self.fn_buffers);
+        }
+    }
+
+    /// Touches each page the frame will span, top to bottom, so that reserving it can't skip
+    /// past the guard page below the stack
+    fn gen_stack_probes(&mut self) {
+        // mov r11, <frame size>
+        // probe_loop:
+        // sub rsp, PAGE_SIZE
+        // mov [rsp], r11
+        // sub r11, PAGE_SIZE
+        // cmp r11, PAGE_SIZE
+        // jae probe_loop
+        // mov rsp, rbp
+        let current = self.current_fn.expect("Not inside a function");
+        let block = self.gen_optional_block(|this| {
+            let probe_loop = this.new_label();
+            let scratch = Register(11);
+            this.opt.accept(Instr::SetConstant(
+                scratch,
+                Constant::Tbd(LabelId::FrameSize(current)),
+            ));
+            this.opt.label(probe_loop);
+            this.opt.accept(Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: PAGE_SIZE as i32,
+            });
+            this.opt.accept(Instr::Store {
+                base: STACK_POINTER,
+                offset: 0,
+                orig: scratch,
+            });
+            this.opt.accept(Instr::SubConstant {
+                dest: scratch,
+                val: PAGE_SIZE as i32,
+            });
+            this.opt.accept(Instr::CompareConstant {
+                lhs: scratch,
+                val: PAGE_SIZE as i32,
+            });
+            this.opt
+                .accept(Instr::JumpIf(Condition::AboveOrEqual, probe_loop));
+            this.opt.accept(Instr::MoveRegs {
+                dest: STACK_POINTER,
+                orig: STACK_FRAME_POINTER,
+            });
+        });
+        self.fn_probe_block = Some(block);
+    }
+
+    /// Traps if the canary of the frame was overwritten
+    fn gen_canary_check(&mut self) {
+        // mov r10, <canary>
+        // mov r10, [r10]
+        // cmp r10, [rbp - 8]
+        // jne <stack smashing>
+        let canary = self.canary_label();
+        let failed = self.trap_label(Trap::StackSmashing);
+        // r10 is neither used for returning nor for calling
+        let scratch = self.registers.allocate(RegisterRequest(10));
+        self.gen_canary_block(|this| {
+            this.opt
+                .accept(Instr::SetConstant(scratch.reg(), Constant::Tbd(canary)));
+            this.opt.accept(Instr::Load {
+                dest: scratch.reg(),
+                base: scratch.reg(),
+                offset: 0,
+            });
+            this.opt.accept(Instr::CompareMem {
+                lhs: scratch.reg(),
+                base: STACK_FRAME_POINTER,
+                offset: CANARY_OFFSET,
+            });
+            this.opt.accept(Instr::JumpIf(Condition::NotEqual, failed));
+        });
+        self.free(scratch);
+    }
+
+    /// Enters the scope of the `st`th symbol table, giving a stack slot to each of its variables
+    fn enter_scope(&mut self, st: usize) {
+        let prev_frame_top = self.frame_top;
+        // Pushed before laying out the slots so that types declared in the scope can be resolved
+        self.scopes.push(Scope {
+            st,
+            slots: HashMap::new(),
+            prev_frame_top,
+        });
+        for id in self.st.entries(st) {
+            if let Some(var) = self.st.get(id).kind.unwrap_variable() {
+                self.fn_buffers |= !var.ty.is_scalar();
+                self.frame_top += self.type_size(&var.ty);
+                let offset = -(self.frame_top as i32);
+                self.scopes.last_mut().unwrap().slots.insert(id, offset);
+            }
+        }
+        self.frame_size = self.frame_size.max(self.frame_top);
+    }
+
+    fn leave_scope(&mut self) {
+        let scope = self.scopes.pop().expect("Not inside a scope");
+        self.frame_top = scope.prev_frame_top;
+    }
+
+    /// Finds the symbol named `name` in the innermost scope declaring it, from the active blocks
+    /// to the function and global scopes, returning it with its slot if it's a local variable
+    fn lookup(&self, name: &str) -> (SE, Option<i32>) {
+        for scope in self.scopes.iter().rev() {
+            if let Some(id) = self.st.find(scope.st, name) {
+                return (id, scope.slots.get(&id).copied());
+            }
+        }
+        let global = self
+            .st
+            .st_of(self.current_fn.expect("Not inside a function"));
+        let id = self
+            .st
+            .find(global, name)
+            .unwrap_or_else(|| panic!("Unknown symbol {name}"));
+        (id, None)
+    }
+
+    /// Finds the local variable named `name`, returning its type and the offset of its slot
+    fn resolve(&self, name: &str) -> (Type, i32) {
+        match self.lookup(name) {
+            (id, Some(offset)) => {
+                let var = self.st.get(id).kind.unwrap_variable().unwrap();
+                (var.ty.clone(), offset)
             }
+            (_, None) => panic!("{name} isn't a local variable"),
+        }
+    }
+
+    fn resolve_enum(&self, name: &str) -> &EnumStEntry {
+        let (id, _) = self.lookup(name);
+        self.st
+            .get(id)
+            .kind
+            .unwrap_enum()
+            .unwrap_or_else(|| panic!("{name} isn't an enum"))
+    }
+
+    fn current_entry(&self) -> &FunctionStEntry {
+        let current = self.current_fn.expect("Not inside a function");
+        self.st
+            .get(current)
+            .kind
+            .unwrap_function()
+            .expect("Current symbol isn't a function")
+    }
+
+    fn current_args(&self) -> &[Type] {
+        &self.current_entry().args
+    }
+
+    fn current_ret(&self) -> &Type {
+        &self.current_entry().ret
+    }
+
+    /// Where the named arguments of the current function (and its hidden return pointer, if
+    /// any, as the first one) were passed
+    fn current_arg_locations(&self) -> Vec<ArgLocation> {
+        let mut types = self.current_args().to_vec();
+        if self.returns_in_memory(self.current_ret()) {
+            types.insert(0, Type::Ptr);
         }
+        arg_locations(&types)
+    }
+
+    /// Offset from the frame pointer of the register save area of a variadic function, after
+    /// the argument slots
+    fn reg_save_area_offset(&self) -> i32 {
+        let in_memory = self.returns_in_memory(self.current_ret());
+        let slots = (self.current_args().len() + usize::from(in_memory)) as u64;
+        -((self.canary_size() + slots * SLOT_SIZE + REG_SAVE_AREA_SIZE) as i32)
+    }
+
+    /// Offset from the frame pointer of the stack slot the hidden return pointer is spilled
+    /// into, after those of the arguments
+    fn ret_ptr_offset(&self) -> i32 {
+        self.arg_offset(self.current_args().len())
+    }
+
+    /// Offset from the frame pointer of the stack slot the `idx`th argument is spilled into
+    const fn arg_offset(&self, idx: usize) -> i32 {
+        -((self.canary_size() + (idx as u64 + 1) * SLOT_SIZE) as i32)
+    }
+
+    /// Size of the slot reserved for the stack canary at the top of every frame
+    const fn canary_size(&self) -> u64 {
+        if self.options.stack_protector {
+            SLOT_SIZE
+        } else {
+            0
+        }
+    }
+
+    fn push(&mut self, reg: Register) {
+        self.opt.accept(Instr::Push(reg));
+        self.stack_depth += 8;
+    }
+
+    fn pop(&mut self, reg: Register) {
+        self.opt.accept(Instr::Pop(reg));
+        self.stack_depth -= 8;
+    }
+
+    fn push_xmm(&mut self, reg: XmmRegister) {
+        self.opt.accept(Instr::SubConstant {
+            dest: STACK_POINTER,
+            val: 8,
+        });
+        self.opt.accept(Instr::StoreXmm {
+            size: FloatSize::Double,
+            base: STACK_POINTER,
+            offset: 0,
+            orig: reg,
+        });
+        self.stack_depth += 8;
+    }
+
+    fn pop_xmm(&mut self, reg: XmmRegister) {
+        self.opt.accept(Instr::LoadXmm {
+            size: FloatSize::Double,
+            dest: reg,
+            base: STACK_POINTER,
+            offset: 0,
+        });
+        self.opt.accept(Instr::AddConstant {
+            dest: STACK_POINTER,
+            val: 8,
+        });
+        self.stack_depth -= 8;
     }
 
     fn free(&mut self, reg: RegAllocation) {
@@ -73,21 +2392,337 @@ impl<SE: PartialEq + Eq + Hash + Debug, S: St<StEntryId = SE>> X64LinuxElfCodege
         self.registers.free(reg);
     }
 
+    fn free_xmm(&mut self, reg: XmmAllocation) {
+        self.opt.accept(Instr::FreeXmmRegister);
+        self.registers.free_xmm(reg);
+    }
+
+    fn free_value_reg(&mut self, reg: ValueReg) {
+        match reg {
+            ValueReg::Gp(reg) => self.free(reg),
+            ValueReg::Xmm(reg) => self.free_xmm(reg),
+        }
+    }
+
+    /// Builds a `va_list` past the named arguments of the current function
+    fn gen_va_start(&mut self) -> Value {
+        assert!(
+            self.current_entry().variadic,
+            "va_start outside of a variadic function"
+        );
+        let locations = self.current_arg_locations();
+        let gp_used = locations
+            .iter()
+            .filter(|loc| matches!(loc, ArgLocation::Gp(_)))
+            .count() as u64;
+        let fp_used = locations.len() as u64 - gp_used;
+        let gp_offset = gp_used * 8;
+        let fp_offset = CALL_ARG_REGISTERS.len() as u64 * 8 + fp_used * 16;
+
+        let offset = self.alloc_buffer(VA_LIST_SIZE);
+        let reg = self.registers.allocate_any();
+        // Both u32 offsets at once
+        self.opt.accept(Instr::SetConstant(
+            reg.reg(),
+            Constant::Value(gp_offset | fp_offset << 32),
+        ));
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset,
+            orig: reg.reg(),
+        });
+        // Arguments not passed in registers are right after the saved rbp & the return address
+        self.opt.accept(Instr::LoadAddress {
+            dest: reg.reg(),
+            base: STACK_FRAME_POINTER,
+            offset: 16,
+        });
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + 8,
+            orig: reg.reg(),
+        });
+        self.opt.accept(Instr::LoadAddress {
+            dest: reg.reg(),
+            base: STACK_FRAME_POINTER,
+            offset: self.reg_save_area_offset(),
+        });
+        self.opt.accept(Instr::Store {
+            base: STACK_FRAME_POINTER,
+            offset: offset + 16,
+            orig: reg.reg(),
+        });
+        self.opt.accept(Instr::LoadAddress {
+            dest: reg.reg(),
+            base: STACK_FRAME_POINTER,
+            offset,
+        });
+        Value::gp(Type::VaList, reg)
+    }
+
+    /// Reads the next argument of type `ty` from the `va_list` at `list`, from the register save
+    /// area while there are registers of its class left and from the stack afterwards
+    fn gen_va_arg(&mut self, list: Register, ty: Type) -> Value {
+        assert!(ty.is_scalar(), "Variadic {ty:?} arguments aren't supported");
+        assert!(
+            ty != Type::F32,
+            "Variadic f32 arguments are promoted to f64"
+        );
+        let save_area_end = REG_SAVE_AREA_SIZE as i32;
+        let (field, limit, step) = if ty.is_float() {
+            (4, save_area_end, 16)
+        } else {
+            (0, CALL_ARG_REGISTERS.len() as i32 * 8, 8)
+        };
+        let value = if ty.is_float() {
+            ValueReg::Xmm(self.registers.allocate_any_xmm())
+        } else {
+            ValueReg::Gp(self.registers.allocate_any())
+        };
+        let load = |ptr: Register| match &value {
+            ValueReg::Gp(reg) => Instr::Load {
+                dest: reg.reg(),
+                base: ptr,
+                offset: 0,
+            },
+            ValueReg::Xmm(reg) => Instr::LoadXmm {
+                size: FloatSize::Double,
+                dest: reg.reg(),
+                base: ptr,
+                offset: 0,
+            },
+        };
+        let offset = self.registers.allocate_any();
+        let ptr = self.registers.allocate_any();
+        let overflow = self.new_label();
+        let end = self.new_label();
+
+        self.opt.accept(Instr::LoadNarrow {
+            size: IntSize::Dword,
+            signed: false,
+            dest: offset.reg(),
+            base: list,
+            offset: field,
+        });
+        self.opt.accept(Instr::CompareConstant {
+            lhs: offset.reg(),
+            val: limit,
+        });
+        self.opt
+            .accept(Instr::JumpIf(Condition::AboveOrEqual, overflow));
+        self.opt.accept(Instr::Load {
+            dest: ptr.reg(),
+            base: list,
+            offset: 16,
+        });
+        self.opt.accept(Instr::IntOp {
+            op: IntOp::Add,
+            dest: ptr.reg(),
+            orig: offset.reg(),
+        });
+        self.opt.accept(load(ptr.reg()));
+        self.opt.accept(Instr::AddConstant {
+            dest: offset.reg(),
+            val: step,
+        });
+        self.opt.accept(Instr::StoreNarrow {
+            size: IntSize::Dword,
+            base: list,
+            offset: field,
+            orig: offset.reg(),
+        });
+        self.opt.accept(Instr::Jump(end));
+
+        self.opt.label(overflow);
+        self.opt.accept(Instr::Load {
+            dest: ptr.reg(),
+            base: list,
+            offset: 8,
+        });
+        self.opt.accept(load(ptr.reg()));
+        self.opt.accept(Instr::AddConstant {
+            dest: ptr.reg(),
+            val: 8,
+        });
+        self.opt.accept(Instr::Store {
+            base: list,
+            offset: 8,
+            orig: ptr.reg(),
+        });
+        self.opt.label(end);
+
+        self.free(offset);
+        self.free(ptr);
+        if let ValueReg::Gp(reg) = &value {
+            self.canonicalize(reg.reg(), &ty);
+        }
+        Value { ty, reg: value }
+    }
+
+    /// Moves an aggregate into the return registers, or copies it through the hidden return
+    /// pointer and returns that in rax
+    fn gen_return_aggregate(&mut self, value: Value) {
+        if self.returns_in_memory(&value.ty) {
+            let dest = self.registers.allocate_any();
+            self.opt.accept(Instr::Load {
+                dest: dest.reg(),
+                base: STACK_FRAME_POINTER,
+                offset: self.ret_ptr_offset(),
+            });
+            self.store_mem(value, dest.reg(), 0);
+            if dest != RETURN_REG {
+                self.opt.accept(Instr::MoveRegs {
+                    dest: Register(RETURN_REG.0),
+                    orig: dest.reg(),
+                });
+            }
+            self.free(dest);
+        } else {
+            let mut regs = self
+                .return_registers(&value.ty)
+                .into_iter()
+                .enumerate()
+                .collect::<Vec<_>>();
+            let addr = value.into_gp();
+            // The register holding the address may be one of the return registers
+            regs.sort_by_key(|(_, reg)| matches!(reg, ArgLocation::Gp(req) if *req == addr));
+            for (i, reg) in regs {
+                let offset = Self::elem_offset(i);
+                match reg {
+                    ArgLocation::Gp(req) => self.opt.accept(Instr::Load {
+                        dest: Register(req.0),
+                        base: addr.reg(),
+                        offset,
+                    }),
+                    ArgLocation::Xmm(req) => self.opt.accept(Instr::LoadXmm {
+                        size: FloatSize::Double,
+                        dest: XmmRegister(req.0),
+                        base: addr.reg(),
+                        offset,
+                    }),
+                }
+            }
+            self.free(addr);
+        }
+    }
+
     fn function_enter_harness(&mut self) {
         // push rbp // push stack frame
         // mov rbp, rsp // save new frame
         self.opt.accept(Instr::Push(STACK_FRAME_POINTER));
-        self.opt.accept(Instr::MoveRegs { dest: STACK_FRAME_POINTER, orig: STACK_POINTER });
+        self.opt.accept(Instr::MoveRegs {
+            dest: STACK_FRAME_POINTER,
+            orig: STACK_POINTER,
+        });
+        // <probe the frame's pages> // if it's larger than a page
+        // sub rsp, <frame size> // reserve the argument & variable slots, keeping rsp 16 byte aligned
+        // mov [rbp - 8 * (i + 1)], <arg i>
+        let current = self.current_fn.expect("Not inside a function");
+        self.gen_stack_probes();
+        self.opt
+            .accept(Instr::ReserveFrame(LabelId::FrameSize(current)));
+        if self.options.stack_protector {
+            // mov r11, <canary>
+            // mov r11, [r11]
+            // mov [rbp - 8], r11
+            let canary = self.canary_label();
+            self.gen_canary_block(|this| {
+                this.opt
+                    .accept(Instr::SetConstant(Register(11), Constant::Tbd(canary)));
+                this.opt.accept(Instr::Load {
+                    dest: Register(11),
+                    base: Register(11),
+                    offset: 0,
+                });
+                this.opt.accept(Instr::Store {
+                    base: STACK_FRAME_POINTER,
+                    offset: CANARY_OFFSET,
+                    orig: Register(11),
+                });
+            });
+        }
+        let args = self.current_args().to_vec();
+        assert!(
+            args.iter().all(|ty| ty.is_scalar() || *ty == Type::VaList),
+            "Taking aggregates by value isn't supported"
+        );
+        // The hidden return pointer is passed before the arguments
+        let in_memory = self.returns_in_memory(self.current_ret());
+        let mut locations = self.current_arg_locations();
+        if in_memory {
+            let ArgLocation::Gp(req) = locations.remove(0) else {
+                unreachable!()
+            };
+            self.opt.accept(Instr::Store {
+                base: STACK_FRAME_POINTER,
+                offset: self.ret_ptr_offset(),
+                orig: Register(req.0),
+            });
+        }
+        self.frame_top =
+            self.canary_size() + (args.len() + usize::from(in_memory)) as u64 * SLOT_SIZE;
+        if self.current_entry().variadic {
+            // mov [save area + 8 * i], <int arg reg i>
+            // movsd [save area + 48 + 16 * i], <float arg reg i>
+            self.frame_top += REG_SAVE_AREA_SIZE;
+            let area = self.reg_save_area_offset();
+            for (i, req) in CALL_ARG_REGISTERS.iter().enumerate() {
+                self.opt.accept(Instr::Store {
+                    base: STACK_FRAME_POINTER,
+                    offset: area + 8 * i as i32,
+                    orig: Register(req.0),
+                });
+            }
+            let floats = area + 8 * CALL_ARG_REGISTERS.len() as i32;
+            for (i, req) in FLOAT_ARG_REGISTERS.iter().enumerate() {
+                self.opt.accept(Instr::StoreXmm {
+                    size: FloatSize::Double,
+                    base: STACK_FRAME_POINTER,
+                    offset: floats + 16 * i as i32,
+                    orig: XmmRegister(req.0),
+                });
+            }
+        }
+        self.frame_size = self.frame_top;
+        for (idx, (ty, loc)) in args.iter().zip(locations).enumerate() {
+            let offset = self.arg_offset(idx);
+            match loc {
+                ArgLocation::Gp(req) => {
+                    self.canonicalize(Register(req.0), ty);
+                    self.opt.accept(Instr::Store {
+                        base: STACK_FRAME_POINTER,
+                        offset,
+                        orig: Register(req.0),
+                    })
+                }
+                ArgLocation::Xmm(req) => self.opt.accept(Instr::StoreXmm {
+                    size: float_size(ty),
+                    base: STACK_FRAME_POINTER,
+                    offset,
+                    orig: XmmRegister(req.0),
+                }),
+            }
+        }
+        self.stack_depth = 0;
     }
 
     fn function_exit_harness(&mut self) {
-        // mov rsp, rbp ; go to stack frame start
-        // pop rbp ; pop the previous stack frame
+        self.leave_frame();
         // ret
-        self.opt.accept(Instr::MoveRegs { dest: STACK_POINTER, orig: STACK_FRAME_POINTER });
-        self.opt.accept(Instr::Pop(STACK_FRAME_POINTER));
         self.opt.accept(Instr::Ret)
+    }
 
+    fn leave_frame(&mut self) {
+        if self.options.stack_protector {
+            self.gen_canary_check();
+        }
+        // mov rsp, rbp ; go to stack frame start
+        // pop rbp ; pop the previous stack frame
+        self.opt.accept(Instr::MoveRegs {
+            dest: STACK_POINTER,
+            orig: STACK_FRAME_POINTER,
+        });
+        self.opt.accept(Instr::Pop(STACK_FRAME_POINTER));
     }
 }
 
@@ -97,89 +2732,200 @@ impl<SE: PartialEq + Eq + Hash + Clone + Copy + Debug, S: St<StEntryId = SE>> Co
     fn enter_fn(&mut self, entry: SE) {
         let label = LabelId::StLabel(entry);
         self.opt.label(label);
-        let entry = self.st.get(entry);
-        if entry.lexeme == "main" {
-            self.main = Some(label);
+        self.current_fn = Some(entry);
+        self.fn_buffers = false;
+        self.defined_fns.insert(entry);
+        let lexeme = &self.st.get(entry).lexeme;
+        if matches!(&self.options.entry, Entry::Main(name) | Entry::Start(name) if name == lexeme) {
+            self.entry = Some(entry);
         }
-        self.function_enter_harness()
+        let entry = self.st.get(entry);
+        let inner_st = entry
+            .kind
+            .unwrap_function()
+            .expect("Entered symbol isn't a function")
+            .inner_st;
+        self.function_enter_harness();
+        self.enter_scope(inner_st);
     }
 
     fn exit_fn(&mut self) {
-        
+        self.leave_scope();
+        for (name, (_, placed)) in self.fn_labels.drain() {
+            assert!(placed, "Jump to undefined label {name}");
+        }
+        self.resolve_canary_blocks();
+        self.gen_trap_pads();
+        let current = self.current_fn.take().expect("Not inside a function");
+        let frame_size = self.frame_size.next_multiple_of(16);
+        let probes = self
+            .fn_probe_block
+            .take()
+            .expect("Frame was never reserved");
+        self.resolve_optional_block(probes, frame_size > PAGE_SIZE);
+        self.opt.add_label(LabelId::FrameSize(current), frame_size);
     }
 
-    fn gen_statement(&mut self, statement: Statement) {
+    fn gen_statement(&mut self, statement: Statement<SE>) {
+        // Temporaries only live until the end of the statement that needs them
+        let frame_top = self.frame_top;
         match statement {
             Statement::Syscall { syscall, args } => {
-                let mut reg = self.gen_expression(syscall);
-                println!("{reg:?} != {SYSCALL_REG:?} is {}", reg != SYSCALL_REG);
-                if reg != SYSCALL_REG {
-                    let old_reg = reg;
-                    reg = self.registers.allocate(SYSCALL_REG);
-                    self.opt.accept(Instr::MoveRegs {
-                        dest: reg.reg(),
-                        orig: old_reg.reg(),
-                    });
-                    self.free(old_reg);
-                }
-                let regs = args
-                    .into_iter()
-                    .enumerate()
-                    .map(|(i, expr)| {
-                        let expected = ARG_REGISTERS[i];
-                        let gotten = self.gen_expression(expr);
-                        if gotten == expected {
-                            gotten
-                        } else {
-                            let expected = self.registers.allocate(expected);
-                            self.opt.accept(Instr::MoveRegs {
-                                dest: expected.reg(),
-                                orig: gotten.reg(),
-                            });
-                            self.free(gotten);
-                            expected
-                        }
-                    })
-                    .collect::<Vec<_>>();
-                self.opt.accept(Instr::Syscall);
-                self.free(reg);
-                for reg in regs {
-                    self.free(reg);
-                }
+                let result = self.gen_syscall(syscall, args);
                 // TODO Keep Return registers if needed
+                self.free(result);
             }
+            Statement::TrySyscall {
+                syscall,
+                args,
+                result,
+                on_error,
+            } => self.gen_try_syscall(syscall, args, result, on_error),
+            Statement::Return {
+                inner: Some(Expression::Call { func, args }),
+            } => self.gen_tail_call(CallTarget::Direct(func), args),
+            Statement::Return {
+                inner:
+                    Some(Expression::CallIndirect {
+                        addr,
+                        args,
+                        ret,
+                        variadic,
+                    }),
+            } => self.gen_tail_call(
+                CallTarget::Indirect {
+                    addr: *addr,
+                    ret,
+                    variadic,
+                },
+                args,
+            ),
             Statement::Return { inner } => {
-                if let Some(expr) = inner {
-                    let reg = self.gen_expression(expr);
-                    let rax = RegisterRequest(0);
-                    if reg != rax {
-                        let rax = self.registers.allocate(rax);
-                        self.opt.accept(Instr::MoveRegs {
-                            dest: rax.reg(),
-                            orig: reg.reg(),
+                let value = inner.map(|expr| self.gen_expression(expr));
+                self.gen_return(value);
+            }
+            Statement::Store { addr, val } => {
+                let addr = self.gen_expression(addr);
+                assert!(
+                    addr.ty.int_repr().is_some(),
+                    "Storing to {:?}, which isn't an address",
+                    addr.ty
+                );
+                let addr = addr.into_gp();
+                let val = self.gen_expression(val);
+                self.store_mem(val, addr.reg(), 0);
+                self.free(addr);
+            }
+            Statement::Label { name } => {
+                let (label, placed) = self.fn_label(&name);
+                assert!(!*placed, "Label {name} is defined twice");
+                *placed = true;
+                let label = *label;
+                self.opt.label(label);
+            }
+            Statement::Goto { name, cond } => {
+                let (label, _) = *self.fn_label(&name);
+                match cond {
+                    Some(cond) => {
+                        let cond = self.gen_expression(cond).into_gp();
+                        self.opt.accept(Instr::CompareConstant {
+                            lhs: cond.reg(),
+                            val: 0,
                         });
-                        self.free(reg);
+                        self.opt.accept(Instr::JumpIf(Condition::NotEqual, label));
+                        self.free(cond);
                     }
+                    None => self.opt.accept(Instr::Jump(label)),
                 }
-                self.function_exit_harness()
             }
+            Statement::Expression { expr } => {
+                let value = self.gen_expression(expr);
+                self.free_value_reg(value.reg);
+            }
+            Statement::Block { st, body } => {
+                self.enter_scope(st);
+                for statement in body {
+                    self.gen_statement(statement);
+                }
+                self.leave_scope();
+            }
+            Statement::Assign { name, val } => {
+                let (ty, offset) = self.resolve(&name);
+                let value = self.gen_expression(val);
+                assert_eq!(value.ty, ty, "Mismatched type assigning to {name}");
+                self.store_slot(value, offset);
+            }
+            Statement::Match {
+                scrutinee,
+                arms,
+                default,
+            } => self.gen_match(scrutinee, arms, default),
+            Statement::Panic { message, location } => self.gen_panic(&message, location),
         }
+        self.frame_top = frame_top;
     }
 
     fn finish(mut self) {
+        let runtime_fns = self.resolve_runtime_fns();
         let mut elf_hdr = ElfFileBuilder::new();
-        let mut entrypoint = None;
+        assert!(
+            self.options.main_stack_size.is_none() || matches!(self.options.entry, Entry::Main(_)),
+            "Only programs started from main can run on a mapped stack"
+        );
         // Add run harness
-        if let Some(main) = self.main {
-            let start = LabelId::CustomLabel(0); // TODO Allocate a label
-            self.opt.label(start);
-            entrypoint = Some(start);
-            self.opt.accept(Instr::Call(main));
-            let arg = self.registers.allocate(ARG_REGISTERS[0]);
-            self.opt.accept(Instr::MoveRegs { dest: arg.reg(), orig: Register(0) });
-            self.opt.accept(Instr::SetConstant(Register(0), Constant::Value(60)));
-            self.opt.accept(Instr::Syscall);
-        }
+        let entrypoint = match self.options.entry.clone() {
+            Entry::Main(name) => {
+                let main = self
+                    .entry
+                    .unwrap_or_else(|| panic!("No function {name} to start from"));
+                let start = self.new_label();
+                self.opt.label(start);
+                self.gen_runtime_init();
+                self.gen_main_args(main);
+                if let Some(size) = self.options.main_stack_size {
+                    self.gen_main_stack(size);
+                }
+                self.opt.accept(Instr::Call(LabelId::StLabel(main)));
+                self.gen_runtime_exit();
+                Some(start)
+            }
+            Entry::Start(name) => {
+                let func = self
+                    .entry
+                    .unwrap_or_else(|| panic!("No function {name} to start from"));
+                let args = &self
+                    .st
+                    .get(func)
+                    .kind
+                    .unwrap_function()
+                    .expect("Entry isn't a function")
+                    .args;
+                assert!(
+                    args.is_empty(),
+                    "{name} is started from, so takes no arguments"
+                );
+                // mov rax, 0
+                // push rax ; the return address, faulting if it returns
+                // jmp <func>
+                let start = self.new_label();
+                self.opt.label(start);
+                self.gen_runtime_init();
+                self.opt
+                    .accept(Instr::SetConstant(Register(0), Constant::Value(0)));
+                self.opt.accept(Instr::Push(Register(0)));
+                self.opt.accept(Instr::Jump(LabelId::StLabel(func)));
+                Some(start)
+            }
+            Entry::None => {
+                assert!(
+                    !self.needs_runtime_init(),
+                    "Routines used need initializing from an entry point"
+                );
+                None
+            }
+        };
+
+        self.gen_runtime(&runtime_fns);
 
         let init_addr = self.opt.get_init_addr();
         let mut data_dir = init_addr + self.opt.get_code_len() as u64;
@@ -189,12 +2935,34 @@ impl<SE: PartialEq + Eq + Hash + Clone + Copy + Debug, S: St<StEntryId = SE>> Co
         for lbl in self.data_labels {
             self.opt.add_label(LabelId::DataAddr(lbl), lbl + data_dir);
         }
-        let entrypoint = entrypoint.and_then(|lbl| self.opt.get_label(&lbl)).unwrap();
-        elf_hdr.set_entrypoint(entrypoint);
+        let rw_data_dir = (data_dir + self.data.len() as u64).next_multiple_of(PAGE_SIZE);
+        for lbl in self.rw_data_labels {
+            self.opt
+                .add_label(LabelId::RwDataAddr(lbl), lbl + rw_data_dir);
+        }
+        for reloc in self.data_relocations {
+            let (segment, start) = match reloc.blob {
+                LabelId::DataAddr(start) => (&mut self.data, start),
+                LabelId::RwDataAddr(start) => (&mut self.rw_data, start),
+                _ => unreachable!(),
+            };
+            let addr = self
+                .opt
+                .get_label(&reloc.target)
+                .unwrap_or_else(|| panic!("Unresolved address of {:?} in data", reloc.target));
+            let idx = start as usize + reloc.offset;
+            segment[idx..idx + 8].copy_from_slice(&addr.to_le_bytes());
+        }
+        if let Some(entrypoint) = entrypoint {
+            elf_hdr.set_entrypoint(self.opt.get_label(&entrypoint).unwrap());
+        }
         let code = self.opt.apply_relocs();
         elf_hdr.add_code_segment(code, init_addr);
         elf_hdr.add_rodata_segment(self.data, data_dir, 4);
-        let mut f = File::create("res.elf").unwrap();
+        if !self.rw_data.is_empty() {
+            elf_hdr.add_data_segment(self.rw_data, rw_data_dir, 8);
+        }
+        let mut f = File::create(&self.output).unwrap();
         elf_hdr.build(&mut f).unwrap();
     }
 }
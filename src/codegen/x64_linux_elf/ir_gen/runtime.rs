@@ -0,0 +1,2985 @@
+//! Routines of the runtime, hand written in IR and emitted once into programs referencing them.
+//! They're called with the SysV ABI, but only clobber caller saved registers.
+//!
+//! The library routines are called like any other function: a function declared in the symbol
+//! table but never given a body is provided by the routine of the same name, whose signature it
+//! must match.
+
+use std::{fmt::Debug, hash::Hash};
+
+use crate::{
+    codegen::x64_linux_elf::ir::{
+        syscall::STACK_POINTER, syscall_table, Condition, Constant, Instr, IntOp, IntSize, Register,
+    },
+    data::{St, Type},
+};
+
+use super::{LabelId, X64LinuxElfCodegen, PAGE_SIZE};
+
+const RAX: Register = Register(0);
+const RCX: Register = Register(1);
+const RDX: Register = Register(2);
+const RBX: Register = Register(3);
+const RSI: Register = Register(6);
+const RDI: Register = Register(7);
+const R8: Register = Register(8);
+const R9: Register = Register(9);
+const R10: Register = Register(10);
+const R11: Register = Register(11);
+
+/// Keys of the auxiliary vector
+pub const AT_NULL: u64 = 0;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_SYSINFO_EHDR: u64 = 33;
+
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+const PROT_NONE: u64 = 0;
+const PROT_READ_WRITE: u64 = 0x1 | 0x2;
+const MAP_PRIVATE_ANONYMOUS: u64 = 0x02 | 0x20;
+/// Exit status of programs whose main stack couldn't be mapped, the one of a stack overflow
+const MAIN_STACK_EXIT_CODE: u64 = 128 + 11;
+/// Results of syscalls above this, as unsigned, are `-errno`
+const ERRNO_THRESHOLD: i32 = -(syscall_table::MAX_ERRNO as i32) - 1;
+
+/// Number of the syscall named `name` in the syscall table
+fn syscall_nr(name: &str) -> u64 {
+    syscall_table::by_name(name)
+        .unwrap_or_else(|| panic!("No syscall named {name}"))
+        .nr
+}
+
+/// The heap hands out blocks from size classes of powers of two, from 16 to 2048 bytes, each
+/// with a free list. Blocks start with an 8 byte header holding their class, or for larger
+/// allocations, the length of the mapping they have to themselves.
+const NUM_SIZE_CLASSES: i32 = 8;
+const MIN_CLASS_SIZE: u64 = 16;
+const MAX_CLASS_SIZE: i32 = 2048;
+const BLOCK_HEADER_SIZE: i32 = 8;
+/// Larger sizes can't be mapped in the 47 bit address space, so fail before adding the header
+/// and rounding up to pages could wrap them around to small ones
+const MAX_ALLOC_SIZE: u64 = 1 << 47;
+/// Size of the mappings small blocks are carved from
+const ARENA_SIZE: i32 = 1 << 20;
+/// The state of the heap: the free lists, then the bounds of the unused part of the arena
+const FREE_LISTS: i32 = 0;
+const ARENA_NEXT: i32 = NUM_SIZE_CLASSES * 8;
+const ARENA_END: i32 = ARENA_NEXT + 8;
+/// Taken around `alloc` & `free` once the program spawns threads
+const HEAP_LOCK: i32 = ARENA_END + 8;
+const HEAP_STATE_SIZE: usize = HEAP_LOCK as usize + 8;
+
+/// Output to stdout & stderr is gathered in a buffer for each, holding the number of bytes
+/// used then the bytes, and written out once full, on `flush`, at exit and before aborting
+const OUTPUT_BUFFER_SIZE: i32 = 4096;
+const OUTPUT_USED: i32 = 0;
+const OUTPUT_DATA: i32 = 8;
+const OUTPUT_STRIDE: i32 = OUTPUT_DATA + OUTPUT_BUFFER_SIZE;
+/// Taken around `buffered_write` & `flush` once the program spawns threads
+const OUTPUT_LOCK: i32 = 2 * OUTPUT_STRIDE;
+const OUTPUT_STATE_SIZE: usize = OUTPUT_LOCK as usize + 8;
+
+/// The handlers registered with `atexit`, after their count, run from the last one registered
+const MAX_ATEXIT_HANDLERS: i32 = 32;
+const ATEXIT_COUNT: i32 = 0;
+const ATEXIT_HANDLERS: i32 = 8;
+/// Taken around `atexit` once the program spawns threads
+const ATEXIT_LOCK: i32 = ATEXIT_HANDLERS + MAX_ATEXIT_HANDLERS * 8;
+const ATEXIT_STATE_SIZE: usize = ATEXIT_LOCK as usize + 8;
+
+/// Threads run on a mapping of their own, with a guard page at the bottom and their control
+/// block at the top, above the stack. The block holds the thread id (cleared by the kernel when
+/// the thread exits), the result of the thread, and its function & argument
+const THREAD_STACK_SIZE: i32 = 1 << 20;
+const THREAD_TID: i32 = 0;
+const THREAD_RESULT: i32 = 8;
+const THREAD_FN: i32 = 16;
+const THREAD_ARG: i32 = 24;
+const THREAD_BLOCK: i32 = THREAD_STACK_SIZE - 32;
+/// `CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM |
+/// CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID`
+const CLONE_THREAD_FLAGS: u64 =
+    0x100 | 0x200 | 0x400 | 0x800 | 0x10000 | 0x40000 | 0x100000 | 0x200000;
+
+const FUTEX_WAIT: u64 = 0;
+const FUTEX_WAKE: u64 = 1;
+/// The futex is only shared by threads of the process. The thread ids cleared by the kernel
+/// are woken without it
+const FUTEX_PRIVATE_FLAG: u64 = 128;
+/// Mutexes are 0 when unlocked, 1 when locked and 2 when locked with threads waiting
+const MUTEX_LOCKED: u64 = 1;
+const MUTEX_CONTENDED: u64 = 2;
+
+/// Values & sizes of the ELF structures of the vDSO
+const PT_LOAD: i32 = 1;
+const PT_DYNAMIC: i32 = 2;
+const DT_HASH: i32 = 4;
+const DT_STRTAB: i32 = 5;
+const DT_SYMTAB: i32 = 6;
+const PHDR_SIZE: i32 = 56;
+const DYN_SIZE: i32 = 16;
+const SYM_SIZE: i32 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Routine {
+    /// Writes the message at rsi, of length rdx, to stderr and exits with status rdi. It never
+    /// returns, so it's jumped to rather than called
+    Abort,
+    /// `auxv(key) -> value`, 0 if `key` isn't in the auxiliary vector
+    Auxv,
+    /// `vdso_symbol(name) -> address` of a NUL terminated symbol name in the vDSO, 0 if there's
+    /// no vDSO or it doesn't define `name`
+    VdsoSymbol,
+    /// `clock_gettime(clock, ts) -> 0 or -errno`, through the vDSO when available
+    ClockGettime,
+    /// `clock_gettime` through a syscall, for kernels without a vDSO
+    ClockGettimeSyscall,
+    /// `print(s: ptr, len: i64) -> i64` writes `len` bytes to stdout, evaluating to `len` or
+    /// `-errno`
+    Print,
+    /// `print_err(s: ptr, len: i64) -> i64` writes `len` bytes to stderr, like `print`
+    PrintErr,
+    /// `buffered_write(fd, s, len) -> len or -errno` to the buffer of stdout or stderr, writing
+    /// it out first if it can't hold `len` more bytes, and bypassing it if it never could
+    BufferedWrite,
+    /// `flush_fd(fd) -> 0 or -errno` writes out the buffer of stdout or stderr
+    FlushFd,
+    /// `flush() -> i64` writes out the buffered output, evaluating to 0 or the first `-errno`
+    Flush,
+    /// `print_i64(val: i64) -> i64` in decimal
+    PrintI64,
+    /// `print_u64(val: u64) -> i64` in decimal
+    PrintU64,
+    /// `print_hex(val: u64) -> i64` in lowercase hexadecimal, without a prefix
+    PrintHex,
+    /// `print_unsigned(val, base)`, shared by the integer printing routines
+    PrintUnsigned,
+    /// `memcpy(dest: ptr, src: ptr, n: i64) -> ptr` copying forwards, returning `dest`
+    Memcpy,
+    /// `memset(dest: ptr, byte: i64, n: i64) -> ptr`, returning `dest`
+    Memset,
+    /// `memcmp(a: ptr, b: ptr, n: i64) -> i64`, the difference of the first differing bytes or 0
+    Memcmp,
+    /// `strlen(s: ptr) -> i64` of a NUL terminated string
+    Strlen,
+    /// `alloc(size: i64) -> ptr`, 8 byte aligned, or 0 if out of memory
+    Alloc,
+    /// `realloc(ptr: ptr, size: i64) -> ptr` moving the block if it can't hold `size` bytes, or
+    /// 0 (leaving `ptr` allocated) if out of memory. A null `ptr` allocates a new block
+    Realloc,
+    /// `free(ptr: ptr) -> i64` of a block from `alloc` or `realloc`, or null, evaluating to 0
+    Free,
+    /// `spawn(func: ptr, arg: ptr) -> ptr` runs `func(arg) -> i64` in a new thread, evaluating
+    /// to a handle to join it with, or 0 if it couldn't be created. The thread has a 1 MiB stack
+    Spawn,
+    /// `join(thread: ptr) -> i64` waits for a thread from `spawn` to return, evaluating to its
+    /// result. Each thread must be joined once, which releases its stack
+    Join,
+    /// `mutex_lock(mutex: ptr) -> i64` of a zeroed 8 byte mutex, evaluating to 0
+    MutexLock,
+    /// `mutex_unlock(mutex: ptr) -> i64` of a mutex locked by the current thread, evaluating to 0
+    MutexUnlock,
+    /// `condvar_wait(condvar: ptr, mutex: ptr) -> i64` unlocks `mutex`, waits for the zeroed 8
+    /// byte `condvar` to be signalled and locks `mutex` again, evaluating to 0. It can wake up
+    /// spuriously, so the condition has to be checked again
+    CondvarWait,
+    /// `condvar_signal(condvar: ptr) -> i64` wakes one waiting thread, evaluating to 0
+    CondvarSignal,
+    /// `condvar_broadcast(condvar: ptr) -> i64` wakes every waiting thread, evaluating to 0
+    CondvarBroadcast,
+    /// `atexit(func: ptr) -> i64` registers `func() -> i64` to be run by `exit`, evaluating to
+    /// 0, or -1 once 32 handlers are registered
+    Atexit,
+    /// `exit(status: i64) -> i64` runs the handlers from `atexit` in reverse order, writes out
+    /// the buffered output and ends every thread of the process. It never returns
+    Exit,
+}
+
+impl Routine {
+    /// The library routine providing functions named `name`
+    pub fn by_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "print" => Self::Print,
+            "print_err" => Self::PrintErr,
+            "flush" => Self::Flush,
+            "print_i64" => Self::PrintI64,
+            "print_u64" => Self::PrintU64,
+            "print_hex" => Self::PrintHex,
+            "memcpy" => Self::Memcpy,
+            "memset" => Self::Memset,
+            "memcmp" => Self::Memcmp,
+            "strlen" => Self::Strlen,
+            "alloc" => Self::Alloc,
+            "realloc" => Self::Realloc,
+            "free" => Self::Free,
+            "spawn" => Self::Spawn,
+            "join" => Self::Join,
+            "mutex_lock" => Self::MutexLock,
+            "mutex_unlock" => Self::MutexUnlock,
+            "condvar_wait" => Self::CondvarWait,
+            "condvar_signal" => Self::CondvarSignal,
+            "condvar_broadcast" => Self::CondvarBroadcast,
+            "atexit" => Self::Atexit,
+            "exit" => Self::Exit,
+            _ => return None,
+        })
+    }
+
+    /// Argument & return types of routines callable from expressions
+    pub fn signature(self) -> (Vec<Type>, Type) {
+        match self {
+            Self::Print | Self::PrintErr => (vec![Type::Ptr, Type::I64], Type::I64),
+            Self::BufferedWrite => (vec![Type::I64, Type::Ptr, Type::I64], Type::I64),
+            Self::FlushFd => (vec![Type::I64], Type::I64),
+            Self::Flush => (vec![], Type::I64),
+            Self::PrintI64 => (vec![Type::I64], Type::I64),
+            Self::PrintU64 | Self::PrintHex => (vec![Type::U64], Type::I64),
+            Self::PrintUnsigned => (vec![Type::U64, Type::I64], Type::I64),
+            Self::Memcpy => (vec![Type::Ptr, Type::Ptr, Type::I64], Type::Ptr),
+            Self::Memset => (vec![Type::Ptr, Type::I64, Type::I64], Type::Ptr),
+            Self::Memcmp => (vec![Type::Ptr, Type::Ptr, Type::I64], Type::I64),
+            Self::Strlen => (vec![Type::Ptr], Type::I64),
+            Self::Alloc => (vec![Type::I64], Type::Ptr),
+            Self::Realloc => (vec![Type::Ptr, Type::I64], Type::Ptr),
+            Self::Free => (vec![Type::Ptr], Type::I64),
+            Self::Spawn => (vec![Type::Ptr, Type::Ptr], Type::Ptr),
+            Self::Join
+            | Self::MutexLock
+            | Self::MutexUnlock
+            | Self::CondvarSignal
+            | Self::CondvarBroadcast
+            | Self::Atexit => (vec![Type::Ptr], Type::I64),
+            Self::Exit => (vec![Type::I64], Type::I64),
+            Self::CondvarWait => (vec![Type::Ptr, Type::Ptr], Type::I64),
+            Self::Auxv => (vec![Type::I64], Type::I64),
+            Self::VdsoSymbol => (vec![Type::Ptr], Type::Ptr),
+            Self::ClockGettime | Self::ClockGettimeSyscall => {
+                (vec![Type::I64, Type::Ptr], Type::I64)
+            }
+            Self::Abort => unreachable!("The abort stub isn't called"),
+        }
+    }
+
+    /// Other routines this one references
+    const fn dependencies(self) -> &'static [Self] {
+        match self {
+            Self::PrintI64 => &[Self::PrintU64, Self::BufferedWrite],
+            Self::PrintU64 | Self::PrintHex => &[Self::PrintUnsigned],
+            Self::Print | Self::PrintErr | Self::PrintUnsigned => &[Self::BufferedWrite],
+            Self::BufferedWrite => &[Self::FlushFd, Self::Memcpy],
+            Self::Flush => &[Self::FlushFd],
+            Self::Abort
+            | Self::Auxv
+            | Self::ClockGettimeSyscall
+            | Self::FlushFd
+            | Self::Memcpy
+            | Self::Memset
+            | Self::Memcmp
+            | Self::Strlen
+            | Self::Alloc
+            | Self::Free
+            | Self::Spawn
+            | Self::Join
+            | Self::MutexLock
+            | Self::MutexUnlock
+            | Self::CondvarSignal
+            | Self::CondvarBroadcast
+            | Self::Atexit
+            | Self::Exit => &[],
+            Self::Realloc => &[Self::Alloc, Self::Free, Self::Memcpy],
+            Self::CondvarWait => &[Self::MutexLock, Self::MutexUnlock],
+            Self::VdsoSymbol => &[Self::Auxv],
+            Self::ClockGettime => &[Self::VdsoSymbol, Self::ClockGettimeSyscall],
+        }
+    }
+
+    /// Whether the routine uses state shared by the threads, so has to hold its lock
+    const fn is_locked(self) -> bool {
+        matches!(
+            self,
+            Self::Alloc | Self::Free | Self::BufferedWrite | Self::Flush | Self::Atexit
+        )
+    }
+}
+
+impl<SE: PartialEq + Eq + Hash + Clone + Copy + Debug, S: St<StEntryId = SE>>
+    X64LinuxElfCodegen<SE, S>
+{
+    /// Marks `routine` (and those it depends on) to be emitted, returning its label
+    pub(super) fn use_routine(&mut self, routine: Routine) -> LabelId<SE> {
+        if self.runtime_used.insert(routine) {
+            for dep in routine.dependencies() {
+                self.use_routine(*dep);
+            }
+        }
+        LabelId::Runtime(routine)
+    }
+
+    /// Finds the routines providing the functions referenced without being defined
+    pub(super) fn resolve_runtime_fns(&mut self) -> Vec<(SE, Routine)> {
+        let mut undefined = self
+            .referenced_fns
+            .difference(&self.defined_fns)
+            .copied()
+            .collect::<Vec<_>>();
+        undefined.sort_by_key(|func| self.st.get(*func).lexeme.clone());
+        undefined
+            .into_iter()
+            .map(|func| {
+                let entry = self.st.get(func);
+                let name = &entry.lexeme;
+                let routine = Routine::by_name(name)
+                    .unwrap_or_else(|| panic!("Function {name} is declared but never defined"));
+                let function = entry
+                    .kind
+                    .unwrap_function()
+                    .expect("Referenced symbol isn't a function");
+                let (args, ret) = routine.signature();
+                assert!(
+                    function.args == args && function.ret == ret && !function.variadic,
+                    "Declaration of {name} doesn't match the runtime's {args:?} -> {ret:?}"
+                );
+                self.use_routine(routine);
+                (func, routine)
+            })
+            .collect()
+    }
+
+    /// Label of `size` bytes of writable data owned by `routine`, zeroed at startup
+    fn routine_data(&mut self, routine: Routine, size: usize) -> LabelId<SE> {
+        let addr = match self.runtime_slots.get(&routine) {
+            Some(addr) => *addr,
+            None => {
+                let addr = self.add_rw_data(&vec![0; size], 8);
+                self.runtime_slots.insert(routine, addr);
+                addr
+            }
+        };
+        LabelId::RwDataAddr(addr)
+    }
+
+    fn emit(&mut self, instrs: impl IntoIterator<Item = Instr<LabelId<SE>>>) {
+        for instr in instrs {
+            self.opt.accept(instr);
+        }
+    }
+
+    /// Whether any routine used keeps state set up by [`Self::gen_runtime_init`]
+    pub(super) fn needs_runtime_init(&self) -> bool {
+        self.stack_protected
+            || self.runtime_used.contains(&Routine::Auxv)
+            || self.runtime_used.contains(&Routine::ClockGettime)
+    }
+
+    /// Initializes the state of the routines used from `_start`, before the stack is touched
+    pub(super) fn gen_runtime_init(&mut self) {
+        // The canary is seeded from the random bytes the kernel passes in the auxiliary vector
+        if self.stack_protected {
+            self.use_routine(Routine::Auxv);
+        }
+        if self.runtime_used.contains(&Routine::Auxv) {
+            // mov rcx, [rsp] ; argc
+            // mov rax, 8
+            // imul rcx, rax
+            // add rcx, rsp
+            // add rcx, 16 ; envp, past argc & argv's NULL
+            // envp_loop:
+            // mov rax, [rcx]
+            // add rcx, 8
+            // cmp rax, 0
+            // jne envp_loop ; the auxv follows envp's NULL
+            // mov rax, <auxv slot>
+            // mov [rax], rcx
+            let envp_loop = self.new_label();
+            let slot = self.routine_data(Routine::Auxv, 8);
+            self.emit([
+                Instr::Load {
+                    dest: RCX,
+                    base: STACK_POINTER,
+                    offset: 0,
+                },
+                Instr::SetConstant(RAX, Constant::Value(8)),
+                Instr::IntOp {
+                    op: IntOp::Mul,
+                    dest: RCX,
+                    orig: RAX,
+                },
+                Instr::IntOp {
+                    op: IntOp::Add,
+                    dest: RCX,
+                    orig: STACK_POINTER,
+                },
+                Instr::AddConstant { dest: RCX, val: 16 },
+            ]);
+            self.opt.label(envp_loop);
+            self.emit([
+                Instr::Load {
+                    dest: RAX,
+                    base: RCX,
+                    offset: 0,
+                },
+                Instr::AddConstant { dest: RCX, val: 8 },
+                Instr::CompareConstant { lhs: RAX, val: 0 },
+                Instr::JumpIf(Condition::NotEqual, envp_loop),
+                Instr::SetConstant(RAX, Constant::Tbd(slot)),
+                Instr::Store {
+                    base: RAX,
+                    offset: 0,
+                    orig: RCX,
+                },
+            ]);
+        }
+        if self.runtime_used.contains(&Routine::ClockGettime) {
+            // mov rdi, <"__vdso_clock_gettime">
+            // call vdso_symbol
+            // cmp rax, 0
+            // jne found
+            // mov rax, <clock_gettime_syscall>
+            // found:
+            // mov rcx, <clock_gettime slot>
+            // mov [rcx], rax
+            let name = self.add_data(b"__vdso_clock_gettime\0", 1);
+            let found = self.new_label();
+            let slot = self.routine_data(Routine::ClockGettime, 8);
+            self.emit([
+                Instr::SetConstant(RDI, Constant::Tbd(LabelId::DataAddr(name))),
+                Instr::Call(LabelId::Runtime(Routine::VdsoSymbol)),
+                Instr::CompareConstant { lhs: RAX, val: 0 },
+                Instr::JumpIf(Condition::NotEqual, found),
+                Instr::SetConstant(
+                    RAX,
+                    Constant::Tbd(LabelId::Runtime(Routine::ClockGettimeSyscall)),
+                ),
+            ]);
+            self.opt.label(found);
+            self.emit([
+                Instr::SetConstant(RCX, Constant::Tbd(slot)),
+                Instr::Store {
+                    base: RCX,
+                    offset: 0,
+                    orig: RAX,
+                },
+            ]);
+        }
+        if self.stack_protected {
+            self.gen_canary_seed();
+        }
+    }
+
+    /// Stores 8 of the random bytes from `AT_RANDOM` as the stack canary, or bytes from
+    /// `getrandom` if there are none
+    fn gen_canary_seed(&mut self) {
+        let canary = self.canary_label();
+        let no_random = self.new_label();
+        let seeded = self.new_label();
+        // mov rdi, AT_RANDOM
+        // call auxv
+        // cmp rax, 0
+        // je no_random
+        // mov rax, [rax]
+        // jmp seeded
+        // no_random:
+        // mov rdi, <canary>
+        // mov rsi, 8
+        // mov rdx, 0
+        // mov rax, 318 ; getrandom
+        // syscall
+        // mov rax, <canary>
+        // mov rax, [rax]
+        // seeded:
+        // mov rcx, -256 ; the low byte is zeroed, so string functions stop at the canary
+        // and rax, rcx
+        // mov rcx, <canary>
+        // mov [rcx], rax
+        self.emit([
+            Instr::SetConstant(RDI, Constant::Value(AT_RANDOM)),
+            Instr::Call(LabelId::Runtime(Routine::Auxv)),
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, no_random),
+            Instr::Load {
+                dest: RAX,
+                base: RAX,
+                offset: 0,
+            },
+            Instr::Jump(seeded),
+        ]);
+        self.opt.label(no_random);
+        self.emit([
+            Instr::SetConstant(RDI, Constant::Tbd(canary)),
+            Instr::SetConstant(RSI, Constant::Value(8)),
+            Instr::SetConstant(RDX, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("getrandom"))),
+            Instr::Syscall,
+            Instr::SetConstant(RAX, Constant::Tbd(canary)),
+            Instr::Load {
+                dest: RAX,
+                base: RAX,
+                offset: 0,
+            },
+        ]);
+        self.opt.label(seeded);
+        self.emit([
+            Instr::SetConstant(RCX, Constant::Value(!0xff)),
+            Instr::And {
+                dest: RAX,
+                orig: RCX,
+            },
+            Instr::SetConstant(RCX, Constant::Tbd(canary)),
+            Instr::Store {
+                base: RCX,
+                offset: 0,
+                orig: RAX,
+            },
+        ]);
+    }
+
+    /// Moves `_start` onto a stack of `size` bytes, rounded up to whole pages, mapped below
+    /// the guard page, keeping the arguments of main in rdi, rsi & rdx
+    pub(super) fn gen_main_stack(&mut self, size: u64) {
+        let len = size.next_multiple_of(PAGE_SIZE) + PAGE_SIZE;
+        let len = i32::try_from(len).expect("Main stack size doesn't fit in 32 bits");
+        let failed = self.new_label();
+        let guarded = self.new_label();
+        // push rdi
+        // push rsi
+        // push rdx
+        // mov rax, <len>
+        // <mmap rax>
+        // cmp rax, -4096
+        // ja failed
+        self.emit([
+            Instr::Push(RDI),
+            Instr::Push(RSI),
+            Instr::Push(RDX),
+            Instr::SetConstant(RAX, Constant::Value(len as u64)),
+        ]);
+        self.gen_mmap(RAX);
+        self.emit([
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::Above, failed),
+        ]);
+
+        // mov r8, rax
+        // mov rdi, rax
+        // mov rsi, PAGE_SIZE
+        // mov rdx, PROT_NONE
+        // mov rax, 10 ; mprotect, making the guard page
+        // syscall
+        // cmp rax, -4096
+        // jbe guarded
+        // failed:
+        // <abort>
+        self.emit([
+            Instr::MoveRegs {
+                dest: R8,
+                orig: RAX,
+            },
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RAX,
+            },
+            Instr::SetConstant(RSI, Constant::Value(PAGE_SIZE)),
+            Instr::SetConstant(RDX, Constant::Value(PROT_NONE)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("mprotect"))),
+            Instr::Syscall,
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::BelowOrEqual, guarded),
+        ]);
+        self.opt.label(failed);
+        let message = b"failed to map the main stack\n";
+        let addr = self.add_data(message, 1);
+        self.gen_abort(
+            Constant::Tbd(LabelId::DataAddr(addr)),
+            message.len() as u64,
+            MAIN_STACK_EXIT_CODE,
+        );
+
+        // guarded:
+        // mov r9, rsp
+        // mov rsp, r8
+        // add rsp, <len> ; page aligned, as the original one was 16 byte aligned
+        // mov rdx, [r9]
+        // mov rsi, [r9 + 8]
+        // mov rdi, [r9 + 16]
+        self.opt.label(guarded);
+        self.emit([
+            Instr::MoveRegs {
+                dest: R9,
+                orig: STACK_POINTER,
+            },
+            Instr::MoveRegs {
+                dest: STACK_POINTER,
+                orig: R8,
+            },
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: len,
+            },
+            Instr::Load {
+                dest: RDX,
+                base: R9,
+                offset: 0,
+            },
+            Instr::Load {
+                dest: RSI,
+                base: R9,
+                offset: 8,
+            },
+            Instr::Load {
+                dest: RDI,
+                base: R9,
+                offset: 16,
+            },
+        ]);
+    }
+
+    /// Exits with the status in rax, once the routines are done with
+    pub(super) fn gen_runtime_exit(&mut self) {
+        // mov rdi, rax
+        self.emit([Instr::MoveRegs {
+            dest: RDI,
+            orig: RAX,
+        }]);
+        // The handlers registered are run by exit
+        if self.runtime_used.contains(&Routine::Atexit)
+            || self.runtime_used.contains(&Routine::Exit)
+        {
+            // call exit ; entering it like calls from functions do, with rsp 8 below alignment
+            let exit = self.use_routine(Routine::Exit);
+            self.emit([Instr::Call(exit)]);
+            return;
+        }
+        if self.runtime_used.contains(&Routine::BufferedWrite) {
+            // push rdi
+            // sub rsp, 8 ; rsp is 16 byte aligned after main returns, as it has to be for calls
+            // call flush
+            // add rsp, 8
+            // pop rdi
+            let flush = self.use_routine(Routine::Flush);
+            self.emit([
+                Instr::Push(RDI),
+                Instr::SubConstant {
+                    dest: STACK_POINTER,
+                    val: 8,
+                },
+                Instr::Call(flush),
+                Instr::AddConstant {
+                    dest: STACK_POINTER,
+                    val: 8,
+                },
+                Instr::Pop(RDI),
+            ]);
+        }
+        // mov rax, 231 ; exit_group, so that threads still running don't keep the process alive
+        // syscall
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("exit_group"))),
+            Instr::Syscall,
+        ]);
+    }
+
+    /// Emits every used routine, along with the functions they provide
+    pub(super) fn gen_runtime(&mut self, runtime_fns: &[(SE, Routine)]) {
+        // Buffered output is written out before aborting or exiting
+        if (self.runtime_used.contains(&Routine::Abort)
+            || self.runtime_used.contains(&Routine::Exit))
+            && self.runtime_used.contains(&Routine::BufferedWrite)
+        {
+            self.use_routine(Routine::Flush);
+        }
+        // Shared state is locked once there can be more than one thread
+        let threaded = self.runtime_used.contains(&Routine::Spawn);
+        if threaded && self.runtime_used.iter().any(|routine| routine.is_locked()) {
+            self.use_routine(Routine::MutexLock);
+            self.use_routine(Routine::MutexUnlock);
+        }
+        for routine in self.runtime_used.clone() {
+            self.opt.label(LabelId::Runtime(routine));
+            for (func, _) in runtime_fns.iter().filter(|(_, r)| *r == routine) {
+                self.opt.label(LabelId::StLabel(*func));
+            }
+            if threaded && routine.is_locked() {
+                self.gen_locked(routine);
+            } else {
+                self.gen_routine(routine);
+            }
+        }
+    }
+
+    fn gen_routine(&mut self, routine: Routine) {
+        match routine {
+            Routine::Abort => self.gen_abort_stub(),
+            Routine::Auxv => self.gen_auxv(),
+            Routine::VdsoSymbol => self.gen_vdso_symbol(),
+            Routine::ClockGettime => self.gen_clock_gettime(),
+            Routine::ClockGettimeSyscall => self.emit([
+                Instr::SetConstant(RAX, Constant::Value(syscall_nr("clock_gettime"))),
+                Instr::Syscall,
+                Instr::Ret,
+            ]),
+            Routine::Print => self.gen_print(STDOUT),
+            Routine::PrintErr => self.gen_print(STDERR),
+            Routine::BufferedWrite => self.gen_buffered_write(),
+            Routine::FlushFd => self.gen_flush_fd(),
+            Routine::Flush => self.gen_flush(),
+            Routine::PrintI64 => self.gen_print_i64(),
+            Routine::PrintU64 | Routine::PrintHex => {
+                // mov rsi, <base>
+                // jmp print_unsigned
+                let base = if routine == Routine::PrintU64 { 10 } else { 16 };
+                self.emit([
+                    Instr::SetConstant(RSI, Constant::Value(base)),
+                    Instr::Jump(LabelId::Runtime(Routine::PrintUnsigned)),
+                ]);
+            }
+            Routine::PrintUnsigned => self.gen_print_unsigned(),
+            Routine::Memcpy => self.gen_memcpy(),
+            Routine::Memset => self.gen_memset(),
+            Routine::Memcmp => self.gen_memcmp(),
+            Routine::Strlen => self.gen_strlen(),
+            Routine::Alloc => self.gen_alloc(),
+            Routine::Realloc => self.gen_realloc(),
+            Routine::Free => self.gen_free(),
+            Routine::Spawn => self.gen_spawn(),
+            Routine::Join => self.gen_join(),
+            Routine::MutexLock => self.gen_mutex_lock(),
+            Routine::MutexUnlock => self.gen_mutex_unlock(),
+            Routine::CondvarWait => self.gen_condvar_wait(),
+            Routine::CondvarSignal => self.gen_condvar_wake(1),
+            Routine::CondvarBroadcast => self.gen_condvar_wake(i32::MAX as u64),
+            Routine::Atexit => self.gen_atexit(),
+            Routine::Exit => self.gen_exit(),
+        }
+    }
+
+    fn gen_abort_stub(&mut self) {
+        if self.runtime_used.contains(&Routine::Flush) {
+            // The stub is jumped to from anywhere, and never returns
+            // mov rax, -16
+            // and rsp, rax ; aligning the stack to 16 bytes
+            // push rdi
+            // push rsi
+            // push rdx
+            // push rdx
+            // call flush
+            // pop rdx
+            // pop rdx
+            // pop rsi
+            // pop rdi
+            self.emit([
+                Instr::SetConstant(RAX, Constant::Value(-16i64 as u64)),
+                Instr::And {
+                    dest: STACK_POINTER,
+                    orig: RAX,
+                },
+                Instr::Push(RDI),
+                Instr::Push(RSI),
+                Instr::Push(RDX),
+                Instr::Push(RDX),
+                Instr::Call(LabelId::Runtime(Routine::Flush)),
+                Instr::Pop(RDX),
+                Instr::Pop(RDX),
+                Instr::Pop(RSI),
+                Instr::Pop(RDI),
+            ]);
+        }
+        // mov rbx, rdi
+        // mov rdi, 2 ; stderr
+        // mov rax, 1 ; write
+        // syscall
+        // mov rdi, rbx
+        // mov rax, 231 ; exit_group
+        // syscall
+        self.emit([
+            Instr::MoveRegs {
+                dest: RBX,
+                orig: RDI,
+            },
+            Instr::SetConstant(RDI, Constant::Value(STDERR)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("write"))),
+            Instr::Syscall,
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RBX,
+            },
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("exit_group"))),
+            Instr::Syscall,
+        ]);
+    }
+
+    fn gen_auxv(&mut self) {
+        // mov rcx, <auxv slot>
+        // mov rcx, [rcx]
+        // entry_loop:
+        // mov rax, [rcx] ; a_type
+        // cmp rax, rdi
+        // je found
+        // cmp rax, 0 ; AT_NULL
+        // je missing
+        // add rcx, 16
+        // jmp entry_loop
+        // found:
+        // mov rax, [rcx + 8] ; a_val
+        // ret
+        // missing:
+        // mov rax, 0
+        // ret
+        let slot = self.routine_data(Routine::Auxv, 8);
+        let entry_loop = self.new_label();
+        let found = self.new_label();
+        let missing = self.new_label();
+        self.emit([
+            Instr::SetConstant(RCX, Constant::Tbd(slot)),
+            Instr::Load {
+                dest: RCX,
+                base: RCX,
+                offset: 0,
+            },
+        ]);
+        self.opt.label(entry_loop);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: RCX,
+                offset: 0,
+            },
+            Instr::Compare { lhs: RAX, rhs: RDI },
+            Instr::JumpIf(Condition::Equal, found),
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: AT_NULL as i32,
+            },
+            Instr::JumpIf(Condition::Equal, missing),
+            Instr::AddConstant { dest: RCX, val: 16 },
+            Instr::Jump(entry_loop),
+        ]);
+        self.opt.label(found);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: RCX,
+                offset: 8,
+            },
+            Instr::Ret,
+        ]);
+        self.opt.label(missing);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    /// Looks the symbol up in the dynamic symbol table of the vDSO, an ELF image mapped by the
+    /// kernel at `AT_SYSINFO_EHDR`. Its `DT_HASH` table gives the number of symbols.
+    fn gen_vdso_symbol(&mut self) {
+        let missing = self.new_label();
+        // mov r8, rdi ; wanted name
+        // mov rdi, AT_SYSINFO_EHDR
+        // call auxv
+        // cmp rax, 0
+        // je missing
+        // mov r9, rax ; image
+        // mov rcx, [r9 + 0x20] ; e_phoff
+        // add rcx, r9
+        // movzx rdx, word [r9 + 0x38] ; e_phnum
+        // mov r10, 0 ; load bias
+        // mov r11, 0 ; dynamic section
+        self.emit([
+            Instr::MoveRegs {
+                dest: R8,
+                orig: RDI,
+            },
+            Instr::SetConstant(RDI, Constant::Value(AT_SYSINFO_EHDR)),
+            Instr::Call(LabelId::Runtime(Routine::Auxv)),
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, missing),
+            Instr::MoveRegs {
+                dest: R9,
+                orig: RAX,
+            },
+            Instr::Load {
+                dest: RCX,
+                base: R9,
+                offset: 0x20,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RCX,
+                orig: R9,
+            },
+            Instr::LoadNarrow {
+                size: IntSize::Word,
+                signed: false,
+                dest: RDX,
+                base: R9,
+                offset: 0x38,
+            },
+            Instr::SetConstant(R10, Constant::Value(0)),
+            Instr::SetConstant(R11, Constant::Value(0)),
+        ]);
+
+        // phdr_loop:
+        // cmp rdx, 0
+        // je phdr_done
+        // mov eax, [rcx] ; p_type
+        // cmp rax, PT_DYNAMIC
+        // jne not_dynamic
+        // mov r11, [rcx + 8] ; p_offset
+        // add r11, r9
+        // not_dynamic:
+        // cmp rax, PT_LOAD
+        // jne next_phdr
+        // mov r10, [rcx + 8] ; p_offset
+        // add r10, r9
+        // mov rsi, [rcx + 16] ; p_vaddr
+        // sub r10, rsi
+        // next_phdr:
+        // add rcx, 56
+        // sub rdx, 1
+        // jmp phdr_loop
+        // phdr_done:
+        // cmp r11, 0
+        // je missing
+        let phdr_loop = self.new_label();
+        let not_dynamic = self.new_label();
+        let next_phdr = self.new_label();
+        let phdr_done = self.new_label();
+        self.opt.label(phdr_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, phdr_done),
+            Instr::LoadNarrow {
+                size: IntSize::Dword,
+                signed: false,
+                dest: RAX,
+                base: RCX,
+                offset: 0,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: PT_DYNAMIC,
+            },
+            Instr::JumpIf(Condition::NotEqual, not_dynamic),
+            Instr::Load {
+                dest: R11,
+                base: RCX,
+                offset: 8,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: R11,
+                orig: R9,
+            },
+        ]);
+        self.opt.label(not_dynamic);
+        self.emit([
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: PT_LOAD,
+            },
+            Instr::JumpIf(Condition::NotEqual, next_phdr),
+            Instr::Load {
+                dest: R10,
+                base: RCX,
+                offset: 8,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: R10,
+                orig: R9,
+            },
+            Instr::Load {
+                dest: RSI,
+                base: RCX,
+                offset: 16,
+            },
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: R10,
+                orig: RSI,
+            },
+        ]);
+        self.opt.label(next_phdr);
+        self.emit([
+            Instr::AddConstant {
+                dest: RCX,
+                val: PHDR_SIZE,
+            },
+            Instr::SubConstant { dest: RDX, val: 1 },
+            Instr::Jump(phdr_loop),
+        ]);
+        self.opt.label(phdr_done);
+        self.emit([
+            Instr::CompareConstant { lhs: R11, val: 0 },
+            Instr::JumpIf(Condition::Equal, missing),
+            Instr::SetConstant(RSI, Constant::Value(0)),
+            Instr::SetConstant(RDI, Constant::Value(0)),
+            Instr::SetConstant(RDX, Constant::Value(0)),
+        ]);
+
+        // Pointers of the dynamic section are relative to the load bias
+        // dyn_loop:
+        // mov rax, [r11] ; d_tag
+        // cmp rax, 0 ; DT_NULL
+        // je dyn_done
+        // mov rcx, [r11 + 8] ; d_ptr
+        // add rcx, r10
+        // cmp rax, DT_STRTAB
+        // jne not_strtab
+        // mov rsi, rcx
+        // not_strtab: ... likewise for DT_SYMTAB into rdi & DT_HASH into rdx
+        // add r11, 16
+        // jmp dyn_loop
+        // dyn_done:
+        let dyn_loop = self.new_label();
+        let dyn_done = self.new_label();
+        self.opt.label(dyn_loop);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: R11,
+                offset: 0,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, dyn_done),
+            Instr::Load {
+                dest: RCX,
+                base: R11,
+                offset: 8,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RCX,
+                orig: R10,
+            },
+        ]);
+        for (tag, dest) in [(DT_STRTAB, RSI), (DT_SYMTAB, RDI), (DT_HASH, RDX)] {
+            let skip = self.new_label();
+            self.emit([
+                Instr::CompareConstant { lhs: RAX, val: tag },
+                Instr::JumpIf(Condition::NotEqual, skip),
+                Instr::MoveRegs { dest, orig: RCX },
+            ]);
+            self.opt.label(skip);
+        }
+        self.emit([
+            Instr::AddConstant {
+                dest: R11,
+                val: DYN_SIZE,
+            },
+            Instr::Jump(dyn_loop),
+        ]);
+        self.opt.label(dyn_done);
+        for table in [RSI, RDI, RDX] {
+            self.emit([
+                Instr::CompareConstant { lhs: table, val: 0 },
+                Instr::JumpIf(Condition::Equal, missing),
+            ]);
+        }
+
+        // mov edx, [rdx + 4] ; nchain, the number of symbols
+        // sym_loop:
+        // cmp rdx, 0
+        // je missing
+        // movzx rax, word [rdi + 6] ; st_shndx
+        // cmp rax, 0 ; undefined
+        // je next_sym
+        // mov eax, [rdi] ; st_name
+        // add rax, rsi
+        // mov rcx, r8
+        // name_loop:
+        // movzx r9, byte [rax]
+        // movzx r11, byte [rcx]
+        // cmp r9, r11
+        // jne next_sym
+        // cmp r9, 0
+        // je found
+        // add rax, 1
+        // add rcx, 1
+        // jmp name_loop
+        // next_sym:
+        // add rdi, 24
+        // sub rdx, 1
+        // jmp sym_loop
+        // found:
+        // mov rax, [rdi + 8] ; st_value
+        // add rax, r10
+        // ret
+        // missing:
+        // mov rax, 0
+        // ret
+        let sym_loop = self.new_label();
+        let name_loop = self.new_label();
+        let next_sym = self.new_label();
+        let found = self.new_label();
+        self.emit([Instr::LoadNarrow {
+            size: IntSize::Dword,
+            signed: false,
+            dest: RDX,
+            base: RDX,
+            offset: 4,
+        }]);
+        self.opt.label(sym_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, missing),
+            Instr::LoadNarrow {
+                size: IntSize::Word,
+                signed: false,
+                dest: RAX,
+                base: RDI,
+                offset: 6,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, next_sym),
+            Instr::LoadNarrow {
+                size: IntSize::Dword,
+                signed: false,
+                dest: RAX,
+                base: RDI,
+                offset: 0,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RAX,
+                orig: RSI,
+            },
+            Instr::MoveRegs {
+                dest: RCX,
+                orig: R8,
+            },
+        ]);
+        self.opt.label(name_loop);
+        self.emit([
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: R9,
+                base: RAX,
+                offset: 0,
+            },
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: R11,
+                base: RCX,
+                offset: 0,
+            },
+            Instr::Compare { lhs: R9, rhs: R11 },
+            Instr::JumpIf(Condition::NotEqual, next_sym),
+            Instr::CompareConstant { lhs: R9, val: 0 },
+            Instr::JumpIf(Condition::Equal, found),
+            Instr::AddConstant { dest: RAX, val: 1 },
+            Instr::AddConstant { dest: RCX, val: 1 },
+            Instr::Jump(name_loop),
+        ]);
+        self.opt.label(next_sym);
+        self.emit([
+            Instr::AddConstant {
+                dest: RDI,
+                val: SYM_SIZE,
+            },
+            Instr::SubConstant { dest: RDX, val: 1 },
+            Instr::Jump(sym_loop),
+        ]);
+        self.opt.label(found);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: RDI,
+                offset: 8,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RAX,
+                orig: R10,
+            },
+            Instr::Ret,
+        ]);
+        self.opt.label(missing);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    fn gen_clock_gettime(&mut self) {
+        // The implementation was resolved at startup
+        // mov rax, <clock_gettime slot>
+        // mov rax, [rax]
+        // jmp rax
+        let slot = self.routine_data(Routine::ClockGettime, 8);
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Tbd(slot)),
+            Instr::Load {
+                dest: RAX,
+                base: RAX,
+                offset: 0,
+            },
+            Instr::JumpIndirect(RAX),
+        ]);
+    }
+
+    fn gen_print(&mut self, fd: u64) {
+        // mov rdx, rsi
+        // mov rsi, rdi
+        // mov rdi, <fd>
+        // jmp buffered_write
+        self.emit([
+            Instr::MoveRegs {
+                dest: RDX,
+                orig: RSI,
+            },
+            Instr::MoveRegs {
+                dest: RSI,
+                orig: RDI,
+            },
+            Instr::SetConstant(RDI, Constant::Value(fd)),
+            Instr::Jump(LabelId::Runtime(Routine::BufferedWrite)),
+        ]);
+    }
+
+    fn gen_print_i64(&mut self) {
+        // cmp rdi, 0
+        // jge print_u64
+        // push rdi
+        // mov rsi, <"-">
+        // mov rdx, 1
+        // mov rdi, 1 ; stdout
+        // call buffered_write
+        // mov rdi, [rsp]
+        // mov rax, 0
+        // sub rax, rdi ; i64::MIN is its own negation, which is right as an unsigned
+        // mov rdi, rax
+        // call print_u64
+        // add rsp, 8
+        // cmp rax, 0
+        // jl done
+        // add rax, 1 ; the sign
+        // done:
+        // ret
+        let minus = self.add_data(b"-", 1);
+        let done = self.new_label();
+        self.emit([
+            Instr::CompareConstant { lhs: RDI, val: 0 },
+            Instr::JumpIf(
+                Condition::GreaterOrEqual,
+                LabelId::Runtime(Routine::PrintU64),
+            ),
+            Instr::Push(RDI),
+            Instr::SetConstant(RSI, Constant::Tbd(LabelId::DataAddr(minus))),
+            Instr::SetConstant(RDX, Constant::Value(1)),
+            Instr::SetConstant(RDI, Constant::Value(STDOUT)),
+            Instr::Call(LabelId::Runtime(Routine::BufferedWrite)),
+            Instr::Load {
+                dest: RDI,
+                base: STACK_POINTER,
+                offset: 0,
+            },
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: RAX,
+                orig: RDI,
+            },
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RAX,
+            },
+            Instr::Call(LabelId::Runtime(Routine::PrintU64)),
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Less, done),
+            Instr::AddConstant { dest: RAX, val: 1 },
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::Ret]);
+    }
+
+    /// Writes the digits of rdi in base rsi from the end of a buffer on the stack backwards
+    fn gen_print_unsigned(&mut self) {
+        // sub rsp, 72
+        // lea rcx, [rsp + 72] ; start of the digits
+        // mov r8, rsi
+        // mov r9, <"0123456789abcdef">
+        // mov rax, rdi
+        // digit_loop:
+        // mov rdx, 0
+        // div r8
+        // add rdx, r9
+        // movzx rdx, byte [rdx]
+        // sub rcx, 1
+        // mov [rcx], dl
+        // cmp rax, 0
+        // jne digit_loop
+        // mov rsi, rcx
+        // lea rdx, [rsp + 72]
+        // sub rdx, rcx
+        // mov rdi, 1 ; stdout
+        // call buffered_write
+        // add rsp, 72
+        // ret
+        // Room for the 64 binary digits of the largest value, and 8 more bytes keeping the stack
+        // 16 byte aligned for the call
+        const BUF_SIZE: i32 = 72;
+        let digits = self.add_data(b"0123456789abcdef", 1);
+        let digit_loop = self.new_label();
+        self.emit([
+            Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: BUF_SIZE,
+            },
+            Instr::LoadAddress {
+                dest: RCX,
+                base: STACK_POINTER,
+                offset: BUF_SIZE,
+            },
+            Instr::MoveRegs {
+                dest: R8,
+                orig: RSI,
+            },
+            Instr::SetConstant(R9, Constant::Tbd(LabelId::DataAddr(digits))),
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: RDI,
+            },
+        ]);
+        self.opt.label(digit_loop);
+        self.emit([
+            Instr::SetConstant(RDX, Constant::Value(0)),
+            Instr::UnsignedDiv(R8),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RDX,
+                orig: R9,
+            },
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: RDX,
+                base: RDX,
+                offset: 0,
+            },
+            Instr::SubConstant { dest: RCX, val: 1 },
+            Instr::StoreNarrow {
+                size: IntSize::Byte,
+                base: RCX,
+                offset: 0,
+                orig: RDX,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::NotEqual, digit_loop),
+            Instr::MoveRegs {
+                dest: RSI,
+                orig: RCX,
+            },
+            Instr::LoadAddress {
+                dest: RDX,
+                base: STACK_POINTER,
+                offset: BUF_SIZE,
+            },
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: RDX,
+                orig: RCX,
+            },
+            Instr::SetConstant(RDI, Constant::Value(STDOUT)),
+            Instr::Call(LabelId::Runtime(Routine::BufferedWrite)),
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: BUF_SIZE,
+            },
+            Instr::Ret,
+        ]);
+    }
+
+    fn gen_memcpy(&mut self) {
+        // mov rax, rdi
+        // word_loop: ; 8 bytes at a time, then the rest byte by byte
+        // cmp rdx, 8
+        // jb byte_loop
+        // mov rcx, [rsi]
+        // mov [rdi], rcx
+        // add rsi, 8
+        // add rdi, 8
+        // sub rdx, 8
+        // jmp word_loop
+        // byte_loop:
+        // cmp rdx, 0
+        // je done
+        // movzx rcx, byte [rsi]
+        // mov [rdi], cl
+        // add rsi, 1
+        // add rdi, 1
+        // sub rdx, 1
+        // jmp byte_loop
+        // done:
+        // ret
+        let word_loop = self.new_label();
+        let byte_loop = self.new_label();
+        let done = self.new_label();
+        self.emit([Instr::MoveRegs {
+            dest: RAX,
+            orig: RDI,
+        }]);
+        self.opt.label(word_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 8 },
+            Instr::JumpIf(Condition::Below, byte_loop),
+            Instr::Load {
+                dest: RCX,
+                base: RSI,
+                offset: 0,
+            },
+            Instr::Store {
+                base: RDI,
+                offset: 0,
+                orig: RCX,
+            },
+            Instr::AddConstant { dest: RSI, val: 8 },
+            Instr::AddConstant { dest: RDI, val: 8 },
+            Instr::SubConstant { dest: RDX, val: 8 },
+            Instr::Jump(word_loop),
+        ]);
+        self.opt.label(byte_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, done),
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: RCX,
+                base: RSI,
+                offset: 0,
+            },
+            Instr::StoreNarrow {
+                size: IntSize::Byte,
+                base: RDI,
+                offset: 0,
+                orig: RCX,
+            },
+            Instr::AddConstant { dest: RSI, val: 1 },
+            Instr::AddConstant { dest: RDI, val: 1 },
+            Instr::SubConstant { dest: RDX, val: 1 },
+            Instr::Jump(byte_loop),
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::Ret]);
+    }
+
+    fn gen_memset(&mut self) {
+        // mov rax, rdi
+        // byte_loop:
+        // cmp rdx, 0
+        // je done
+        // mov [rdi], sil
+        // add rdi, 1
+        // sub rdx, 1
+        // jmp byte_loop
+        // done:
+        // ret
+        let byte_loop = self.new_label();
+        let done = self.new_label();
+        self.emit([Instr::MoveRegs {
+            dest: RAX,
+            orig: RDI,
+        }]);
+        self.opt.label(byte_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, done),
+            Instr::StoreNarrow {
+                size: IntSize::Byte,
+                base: RDI,
+                offset: 0,
+                orig: RSI,
+            },
+            Instr::AddConstant { dest: RDI, val: 1 },
+            Instr::SubConstant { dest: RDX, val: 1 },
+            Instr::Jump(byte_loop),
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::Ret]);
+    }
+
+    fn gen_memcmp(&mut self) {
+        // byte_loop:
+        // cmp rdx, 0
+        // je equal
+        // movzx rax, byte [rdi]
+        // movzx rcx, byte [rsi]
+        // cmp rax, rcx
+        // jne differ
+        // add rdi, 1
+        // add rsi, 1
+        // sub rdx, 1
+        // jmp byte_loop
+        // differ:
+        // sub rax, rcx
+        // ret
+        // equal:
+        // mov rax, 0
+        // ret
+        let byte_loop = self.new_label();
+        let differ = self.new_label();
+        let equal = self.new_label();
+        self.opt.label(byte_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, equal),
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: RAX,
+                base: RDI,
+                offset: 0,
+            },
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: RCX,
+                base: RSI,
+                offset: 0,
+            },
+            Instr::Compare { lhs: RAX, rhs: RCX },
+            Instr::JumpIf(Condition::NotEqual, differ),
+            Instr::AddConstant { dest: RDI, val: 1 },
+            Instr::AddConstant { dest: RSI, val: 1 },
+            Instr::SubConstant { dest: RDX, val: 1 },
+            Instr::Jump(byte_loop),
+        ]);
+        self.opt.label(differ);
+        self.emit([
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: RAX,
+                orig: RCX,
+            },
+            Instr::Ret,
+        ]);
+        self.opt.label(equal);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    fn gen_strlen(&mut self) {
+        // mov rax, rdi
+        // byte_loop:
+        // movzx rcx, byte [rax]
+        // cmp rcx, 0
+        // je done
+        // add rax, 1
+        // jmp byte_loop
+        // done:
+        // sub rax, rdi
+        // ret
+        let byte_loop = self.new_label();
+        let done = self.new_label();
+        self.emit([Instr::MoveRegs {
+            dest: RAX,
+            orig: RDI,
+        }]);
+        self.opt.label(byte_loop);
+        self.emit([
+            Instr::LoadNarrow {
+                size: IntSize::Byte,
+                signed: false,
+                dest: RCX,
+                base: RAX,
+                offset: 0,
+            },
+            Instr::CompareConstant { lhs: RCX, val: 0 },
+            Instr::JumpIf(Condition::Equal, done),
+            Instr::AddConstant { dest: RAX, val: 1 },
+            Instr::Jump(byte_loop),
+        ]);
+        self.opt.label(done);
+        self.emit([
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: RAX,
+                orig: RDI,
+            },
+            Instr::Ret,
+        ]);
+    }
+
+    /// Points r8 at the output buffer of the fd in rdi, stdout or stderr
+    fn gen_output_buffer(&mut self) {
+        // mov r8, <output>
+        // cmp rdi, 1 ; stdout
+        // je stdout
+        // add r8, OUTPUT_STRIDE
+        // stdout:
+        let output = self.routine_data(Routine::BufferedWrite, OUTPUT_STATE_SIZE);
+        let stdout = self.new_label();
+        self.emit([
+            Instr::SetConstant(R8, Constant::Tbd(output)),
+            Instr::CompareConstant {
+                lhs: RDI,
+                val: STDOUT as i32,
+            },
+            Instr::JumpIf(Condition::Equal, stdout),
+            Instr::AddConstant {
+                dest: R8,
+                val: OUTPUT_STRIDE,
+            },
+        ]);
+        self.opt.label(stdout);
+    }
+
+    fn gen_buffered_write(&mut self) {
+        let append = self.new_label();
+        let done = self.new_label();
+        // <r8 = buffer of rdi>
+        // mov rax, [r8 + OUTPUT_USED]
+        // add rax, rdx
+        // cmp rax, OUTPUT_BUFFER_SIZE
+        // jbe append
+        // push rdi
+        // push rsi
+        // push rdx
+        // push r8
+        // sub rsp, 8 ; keeping the stack 16 byte aligned
+        // call flush_fd
+        // add rsp, 8
+        // pop r8
+        // pop rdx
+        // pop rsi
+        // pop rdi
+        // cmp rax, 0
+        // jne done
+        // cmp rdx, OUTPUT_BUFFER_SIZE
+        // jbe append
+        // mov rax, 1 ; write, as it would never fit
+        // syscall
+        // done:
+        // ret
+        self.gen_output_buffer();
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: R8,
+                offset: OUTPUT_USED,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RAX,
+                orig: RDX,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: OUTPUT_BUFFER_SIZE,
+            },
+            Instr::JumpIf(Condition::BelowOrEqual, append),
+            Instr::Push(RDI),
+            Instr::Push(RSI),
+            Instr::Push(RDX),
+            Instr::Push(R8),
+            Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Call(LabelId::Runtime(Routine::FlushFd)),
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Pop(R8),
+            Instr::Pop(RDX),
+            Instr::Pop(RSI),
+            Instr::Pop(RDI),
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::NotEqual, done),
+            Instr::CompareConstant {
+                lhs: RDX,
+                val: OUTPUT_BUFFER_SIZE,
+            },
+            Instr::JumpIf(Condition::BelowOrEqual, append),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("write"))),
+            Instr::Syscall,
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::Ret]);
+
+        // append:
+        // mov rax, [r8 + OUTPUT_USED]
+        // mov rcx, rax
+        // add rcx, rdx
+        // mov [r8 + OUTPUT_USED], rcx
+        // lea rdi, [r8 + OUTPUT_DATA]
+        // add rdi, rax
+        // push rdx
+        // call memcpy
+        // pop rax
+        // ret
+        self.opt.label(append);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: R8,
+                offset: OUTPUT_USED,
+            },
+            Instr::MoveRegs {
+                dest: RCX,
+                orig: RAX,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RCX,
+                orig: RDX,
+            },
+            Instr::Store {
+                base: R8,
+                offset: OUTPUT_USED,
+                orig: RCX,
+            },
+            Instr::LoadAddress {
+                dest: RDI,
+                base: R8,
+                offset: OUTPUT_DATA,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RDI,
+                orig: RAX,
+            },
+            Instr::Push(RDX),
+            Instr::Call(LabelId::Runtime(Routine::Memcpy)),
+            Instr::Pop(RAX),
+            Instr::Ret,
+        ]);
+    }
+
+    fn gen_flush_fd(&mut self) {
+        let write_loop = self.new_label();
+        let flushed = self.new_label();
+        let failed = self.new_label();
+        // <r8 = buffer of rdi>
+        // lea rsi, [r8 + OUTPUT_DATA]
+        // mov rdx, [r8 + OUTPUT_USED]
+        // mov rax, 0
+        // mov [r8 + OUTPUT_USED], rax ; what fails to be written is dropped
+        // write_loop:
+        // cmp rdx, 0
+        // je flushed
+        // mov rax, 1 ; write
+        // syscall
+        // cmp rax, 0
+        // jl failed
+        // add rsi, rax
+        // sub rdx, rax
+        // jmp write_loop
+        // flushed:
+        // mov rax, 0
+        // failed:
+        // ret
+        self.gen_output_buffer();
+        self.emit([
+            Instr::LoadAddress {
+                dest: RSI,
+                base: R8,
+                offset: OUTPUT_DATA,
+            },
+            Instr::Load {
+                dest: RDX,
+                base: R8,
+                offset: OUTPUT_USED,
+            },
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::Store {
+                base: R8,
+                offset: OUTPUT_USED,
+                orig: RAX,
+            },
+        ]);
+        self.opt.label(write_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, flushed),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("write"))),
+            Instr::Syscall,
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Less, failed),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RSI,
+                orig: RAX,
+            },
+            Instr::IntOp {
+                op: IntOp::Sub,
+                dest: RDX,
+                orig: RAX,
+            },
+            Instr::Jump(write_loop),
+        ]);
+        self.opt.label(flushed);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0))]);
+        self.opt.label(failed);
+        self.emit([Instr::Ret]);
+    }
+
+    fn gen_atexit(&mut self) {
+        let state = self.routine_data(Routine::Atexit, ATEXIT_STATE_SIZE);
+        let full = self.new_label();
+        // mov rax, <state>
+        // mov rcx, [rax + ATEXIT_COUNT]
+        // cmp rcx, MAX_ATEXIT_HANDLERS
+        // jae full
+        // mov rdx, 8
+        // imul rdx, rcx
+        // add rdx, rax
+        // mov [rdx + ATEXIT_HANDLERS], rdi
+        // add rcx, 1
+        // mov [rax + ATEXIT_COUNT], rcx
+        // mov rax, 0
+        // ret
+        // full:
+        // mov rax, -1
+        // ret
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Tbd(state)),
+            Instr::Load {
+                dest: RCX,
+                base: RAX,
+                offset: ATEXIT_COUNT,
+            },
+            Instr::CompareConstant {
+                lhs: RCX,
+                val: MAX_ATEXIT_HANDLERS,
+            },
+            Instr::JumpIf(Condition::AboveOrEqual, full),
+            Instr::SetConstant(RDX, Constant::Value(8)),
+            Instr::IntOp {
+                op: IntOp::Mul,
+                dest: RDX,
+                orig: RCX,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RDX,
+                orig: RAX,
+            },
+            Instr::Store {
+                base: RDX,
+                offset: ATEXIT_HANDLERS,
+                orig: RDI,
+            },
+            Instr::AddConstant { dest: RCX, val: 1 },
+            Instr::Store {
+                base: RAX,
+                offset: ATEXIT_COUNT,
+                orig: RCX,
+            },
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::Ret,
+        ]);
+        self.opt.label(full);
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(u64::MAX)),
+            Instr::Ret,
+        ]);
+    }
+
+    fn gen_exit(&mut self) {
+        // push rdi ; the status, also aligning the stack for the calls
+        self.emit([Instr::Push(RDI)]);
+        if self.runtime_used.contains(&Routine::Atexit) {
+            // Each handler is unregistered before it runs, so that one calling exit carries on
+            // with those registered before it
+            // handlers_loop:
+            // mov rax, <state>
+            // mov rcx, [rax + ATEXIT_COUNT]
+            // cmp rcx, 0
+            // je handlers_done
+            // sub rcx, 1
+            // mov [rax + ATEXIT_COUNT], rcx
+            // mov rdx, 8
+            // imul rdx, rcx
+            // add rdx, rax
+            // mov rax, [rdx + ATEXIT_HANDLERS]
+            // call rax
+            // jmp handlers_loop
+            // handlers_done:
+            let state = self.routine_data(Routine::Atexit, ATEXIT_STATE_SIZE);
+            let handlers_loop = self.new_label();
+            let handlers_done = self.new_label();
+            self.opt.label(handlers_loop);
+            self.emit([
+                Instr::SetConstant(RAX, Constant::Tbd(state)),
+                Instr::Load {
+                    dest: RCX,
+                    base: RAX,
+                    offset: ATEXIT_COUNT,
+                },
+                Instr::CompareConstant { lhs: RCX, val: 0 },
+                Instr::JumpIf(Condition::Equal, handlers_done),
+                Instr::SubConstant { dest: RCX, val: 1 },
+                Instr::Store {
+                    base: RAX,
+                    offset: ATEXIT_COUNT,
+                    orig: RCX,
+                },
+                Instr::SetConstant(RDX, Constant::Value(8)),
+                Instr::IntOp {
+                    op: IntOp::Mul,
+                    dest: RDX,
+                    orig: RCX,
+                },
+                Instr::IntOp {
+                    op: IntOp::Add,
+                    dest: RDX,
+                    orig: RAX,
+                },
+                Instr::Load {
+                    dest: RAX,
+                    base: RDX,
+                    offset: ATEXIT_HANDLERS,
+                },
+                Instr::CallIndirect(RAX),
+                Instr::Jump(handlers_loop),
+            ]);
+            self.opt.label(handlers_done);
+        }
+        if self.runtime_used.contains(&Routine::Flush) {
+            // call flush
+            self.emit([Instr::Call(LabelId::Runtime(Routine::Flush))]);
+        }
+        // pop rdi
+        // mov rax, 231 ; exit_group
+        // syscall
+        self.emit([
+            Instr::Pop(RDI),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("exit_group"))),
+            Instr::Syscall,
+        ]);
+    }
+
+    fn gen_flush(&mut self) {
+        let stdout_ok = self.new_label();
+        // sub rsp, 8 ; the result for stdout, keeping the stack 16 byte aligned
+        // mov rdi, 1 ; stdout
+        // call flush_fd
+        // mov [rsp], rax
+        // mov rdi, 2 ; stderr
+        // call flush_fd
+        // mov rcx, [rsp]
+        // cmp rcx, 0
+        // je stdout_ok
+        // mov rax, rcx
+        // stdout_ok:
+        // add rsp, 8
+        // ret
+        self.emit([
+            Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::SetConstant(RDI, Constant::Value(STDOUT)),
+            Instr::Call(LabelId::Runtime(Routine::FlushFd)),
+            Instr::Store {
+                base: STACK_POINTER,
+                offset: 0,
+                orig: RAX,
+            },
+            Instr::SetConstant(RDI, Constant::Value(STDERR)),
+            Instr::Call(LabelId::Runtime(Routine::FlushFd)),
+            Instr::Load {
+                dest: RCX,
+                base: STACK_POINTER,
+                offset: 0,
+            },
+            Instr::CompareConstant { lhs: RCX, val: 0 },
+            Instr::JumpIf(Condition::Equal, stdout_ok),
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: RCX,
+            },
+        ]);
+        self.opt.label(stdout_ok);
+        self.emit([
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Ret,
+        ]);
+    }
+
+    /// `mmap`s `len` anonymous read & write bytes, leaving the address (or `-errno`) in rax.
+    /// Clobbers rcx & r11 besides the argument registers
+    fn gen_mmap(&mut self, len: Register) {
+        // mov rsi, <len>
+        // mov rdi, 0
+        // mov rdx, PROT_READ | PROT_WRITE
+        // mov r10, MAP_PRIVATE | MAP_ANONYMOUS
+        // mov r8, -1
+        // mov r9, 0
+        // mov rax, 9 ; mmap
+        // syscall
+        self.emit([
+            Instr::MoveRegs {
+                dest: RSI,
+                orig: len,
+            },
+            Instr::SetConstant(RDI, Constant::Value(0)),
+            Instr::SetConstant(RDX, Constant::Value(PROT_READ_WRITE)),
+            Instr::SetConstant(R10, Constant::Value(MAP_PRIVATE_ANONYMOUS)),
+            Instr::SetConstant(R8, Constant::Value(u64::MAX)),
+            Instr::SetConstant(R9, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("mmap"))),
+            Instr::Syscall,
+        ]);
+    }
+
+    fn gen_alloc(&mut self) {
+        let heap = self.routine_data(Routine::Alloc, HEAP_STATE_SIZE);
+        let class_loop = self.new_label();
+        let class_found = self.new_label();
+        let carve = self.new_label();
+        let refill = self.new_label();
+        let large = self.new_label();
+        let fail = self.new_label();
+        // mov rcx, MAX_ALLOC_SIZE
+        // cmp rdi, rcx
+        // ja fail
+        // mov rax, rdi
+        // add rax, 8 ; header
+        // cmp rax, 2048
+        // ja large
+        // mov rcx, 0 ; class
+        // mov rdx, 16 ; size of the class
+        // class_loop:
+        // cmp rdx, rax
+        // jae class_found
+        // add rdx, rdx
+        // add rcx, 1
+        // jmp class_loop
+        self.emit([
+            Instr::SetConstant(RCX, Constant::Value(MAX_ALLOC_SIZE)),
+            Instr::Compare { lhs: RDI, rhs: RCX },
+            Instr::JumpIf(Condition::Above, fail),
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: RDI,
+            },
+            Instr::AddConstant {
+                dest: RAX,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: MAX_CLASS_SIZE,
+            },
+            Instr::JumpIf(Condition::Above, large),
+            Instr::SetConstant(RCX, Constant::Value(0)),
+            Instr::SetConstant(RDX, Constant::Value(MIN_CLASS_SIZE)),
+        ]);
+        self.opt.label(class_loop);
+        self.emit([
+            Instr::Compare { lhs: RDX, rhs: RAX },
+            Instr::JumpIf(Condition::AboveOrEqual, class_found),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RDX,
+                orig: RDX,
+            },
+            Instr::AddConstant { dest: RCX, val: 1 },
+            Instr::Jump(class_loop),
+        ]);
+
+        // Reuse the first free block of the class
+        // class_found:
+        // mov r8, 8
+        // imul r8, rcx
+        // mov r9, <heap>
+        // add r8, r9 ; free list head
+        // mov rax, [r8]
+        // cmp rax, 0
+        // je carve
+        // mov r9, [rax + 8] ; next free block
+        // mov [r8], r9
+        // add rax, 8
+        // ret
+        self.opt.label(class_found);
+        self.emit([
+            Instr::SetConstant(R8, Constant::Value(8)),
+            Instr::IntOp {
+                op: IntOp::Mul,
+                dest: R8,
+                orig: RCX,
+            },
+            Instr::SetConstant(R9, Constant::Tbd(heap)),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: R8,
+                orig: R9,
+            },
+            Instr::Load {
+                dest: RAX,
+                base: R8,
+                offset: FREE_LISTS,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, carve),
+            Instr::Load {
+                dest: R9,
+                base: RAX,
+                offset: BLOCK_HEADER_SIZE,
+            },
+            Instr::Store {
+                base: R8,
+                offset: FREE_LISTS,
+                orig: R9,
+            },
+            Instr::AddConstant {
+                dest: RAX,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::Ret,
+        ]);
+
+        // Otherwise take a new one from the arena
+        // carve:
+        // mov r8, <heap>
+        // mov rax, [r8 + ARENA_NEXT]
+        // mov r9, rax
+        // add r9, rdx
+        // mov r10, [r8 + ARENA_END]
+        // cmp r9, r10
+        // ja refill
+        // mov [r8 + ARENA_NEXT], r9
+        // mov [rax], rcx ; header
+        // add rax, 8
+        // ret
+        self.opt.label(carve);
+        self.emit([
+            Instr::SetConstant(R8, Constant::Tbd(heap)),
+            Instr::Load {
+                dest: RAX,
+                base: R8,
+                offset: ARENA_NEXT,
+            },
+            Instr::MoveRegs {
+                dest: R9,
+                orig: RAX,
+            },
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: R9,
+                orig: RDX,
+            },
+            Instr::Load {
+                dest: R10,
+                base: R8,
+                offset: ARENA_END,
+            },
+            Instr::Compare { lhs: R9, rhs: R10 },
+            Instr::JumpIf(Condition::Above, refill),
+            Instr::Store {
+                base: R8,
+                offset: ARENA_NEXT,
+                orig: R9,
+            },
+            Instr::Store {
+                base: RAX,
+                offset: 0,
+                orig: RCX,
+            },
+            Instr::AddConstant {
+                dest: RAX,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::Ret,
+        ]);
+
+        // The rest of the previous arena is left unused
+        // refill:
+        // push rcx
+        // push rdx
+        // mov rax, ARENA_SIZE
+        // mmap(rax)
+        // pop rdx
+        // pop rcx
+        // cmp rax, -4096
+        // ja fail
+        // mov r8, <heap>
+        // mov [r8 + ARENA_NEXT], rax
+        // add rax, ARENA_SIZE
+        // mov [r8 + ARENA_END], rax
+        // jmp carve
+        self.opt.label(refill);
+        self.emit([
+            Instr::Push(RCX),
+            Instr::Push(RDX),
+            Instr::SetConstant(RAX, Constant::Value(ARENA_SIZE as u64)),
+        ]);
+        self.gen_mmap(RAX);
+        self.emit([
+            Instr::Pop(RDX),
+            Instr::Pop(RCX),
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::Above, fail),
+            Instr::SetConstant(R8, Constant::Tbd(heap)),
+            Instr::Store {
+                base: R8,
+                offset: ARENA_NEXT,
+                orig: RAX,
+            },
+            Instr::AddConstant {
+                dest: RAX,
+                val: ARENA_SIZE,
+            },
+            Instr::Store {
+                base: R8,
+                offset: ARENA_END,
+                orig: RAX,
+            },
+            Instr::Jump(carve),
+        ]);
+
+        // large:
+        // add rax, 4095
+        // mov rcx, -4096
+        // and rax, rcx ; whole pages
+        // push rax
+        // mmap(rax)
+        // pop rcx
+        // cmp rax, -4096
+        // ja fail
+        // mov [rax], rcx ; header
+        // add rax, 8
+        // ret
+        // fail:
+        // mov rax, 0
+        // ret
+        self.opt.label(large);
+        self.emit([
+            Instr::AddConstant {
+                dest: RAX,
+                val: PAGE_SIZE as i32 - 1,
+            },
+            Instr::SetConstant(RCX, Constant::Value(!(PAGE_SIZE - 1))),
+            Instr::And {
+                dest: RAX,
+                orig: RCX,
+            },
+            Instr::Push(RAX),
+        ]);
+        self.gen_mmap(RAX);
+        self.emit([
+            Instr::Pop(RCX),
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::Above, fail),
+            Instr::Store {
+                base: RAX,
+                offset: 0,
+                orig: RCX,
+            },
+            Instr::AddConstant {
+                dest: RAX,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::Ret,
+        ]);
+        self.opt.label(fail);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    fn gen_free(&mut self) {
+        let heap = self.routine_data(Routine::Alloc, HEAP_STATE_SIZE);
+        let done = self.new_label();
+        let large = self.new_label();
+        // cmp rdi, 0
+        // je done
+        // sub rdi, 8 ; block
+        // mov rax, [rdi] ; header
+        // cmp rax, NUM_SIZE_CLASSES
+        // jae large
+        // mov rcx, 8
+        // imul rax, rcx
+        // mov rcx, <heap>
+        // add rax, rcx ; free list head
+        // mov rcx, [rax]
+        // mov [rdi + 8], rcx
+        // mov [rax], rdi
+        // done:
+        // mov rax, 0
+        // ret
+        self.emit([
+            Instr::CompareConstant { lhs: RDI, val: 0 },
+            Instr::JumpIf(Condition::Equal, done),
+            Instr::SubConstant {
+                dest: RDI,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::Load {
+                dest: RAX,
+                base: RDI,
+                offset: 0,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: NUM_SIZE_CLASSES,
+            },
+            Instr::JumpIf(Condition::AboveOrEqual, large),
+            Instr::SetConstant(RCX, Constant::Value(8)),
+            Instr::IntOp {
+                op: IntOp::Mul,
+                dest: RAX,
+                orig: RCX,
+            },
+            Instr::SetConstant(RCX, Constant::Tbd(heap)),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RAX,
+                orig: RCX,
+            },
+            Instr::Load {
+                dest: RCX,
+                base: RAX,
+                offset: FREE_LISTS,
+            },
+            Instr::Store {
+                base: RDI,
+                offset: BLOCK_HEADER_SIZE,
+                orig: RCX,
+            },
+            Instr::Store {
+                base: RAX,
+                offset: FREE_LISTS,
+                orig: RDI,
+            },
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+
+        // large:
+        // mov rsi, rax
+        // mov rax, 11 ; munmap
+        // syscall
+        // mov rax, 0
+        // ret
+        self.opt.label(large);
+        self.emit([
+            Instr::MoveRegs {
+                dest: RSI,
+                orig: RAX,
+            },
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("munmap"))),
+            Instr::Syscall,
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::Ret,
+        ]);
+    }
+
+    fn gen_realloc(&mut self) {
+        let existing = self.new_label();
+        let cap_loop = self.new_label();
+        let large = self.new_label();
+        let cap_done = self.new_label();
+        let grow = self.new_label();
+        let fail = self.new_label();
+        // cmp rdi, 0
+        // jne existing
+        // mov rdi, rsi
+        // jmp alloc
+        self.emit([
+            Instr::CompareConstant { lhs: RDI, val: 0 },
+            Instr::JumpIf(Condition::NotEqual, existing),
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RSI,
+            },
+            Instr::Jump(LabelId::Runtime(Routine::Alloc)),
+        ]);
+
+        // The capacity of the block, from its header
+        // existing:
+        // mov rax, [rdi - 8]
+        // cmp rax, NUM_SIZE_CLASSES
+        // jae large
+        // mov rcx, 16
+        // cap_loop:
+        // cmp rax, 0
+        // je cap_done
+        // add rcx, rcx
+        // sub rax, 1
+        // jmp cap_loop
+        // large:
+        // mov rcx, rax
+        // cap_done:
+        // sub rcx, 8
+        // cmp rsi, rcx
+        // ja grow
+        // mov rax, rdi
+        // ret
+        self.opt.label(existing);
+        self.emit([
+            Instr::Load {
+                dest: RAX,
+                base: RDI,
+                offset: -BLOCK_HEADER_SIZE,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: NUM_SIZE_CLASSES,
+            },
+            Instr::JumpIf(Condition::AboveOrEqual, large),
+            Instr::SetConstant(RCX, Constant::Value(MIN_CLASS_SIZE)),
+        ]);
+        self.opt.label(cap_loop);
+        self.emit([
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, cap_done),
+            Instr::IntOp {
+                op: IntOp::Add,
+                dest: RCX,
+                orig: RCX,
+            },
+            Instr::SubConstant { dest: RAX, val: 1 },
+            Instr::Jump(cap_loop),
+        ]);
+        self.opt.label(large);
+        self.emit([Instr::MoveRegs {
+            dest: RCX,
+            orig: RAX,
+        }]);
+        self.opt.label(cap_done);
+        self.emit([
+            Instr::SubConstant {
+                dest: RCX,
+                val: BLOCK_HEADER_SIZE,
+            },
+            Instr::Compare { lhs: RSI, rhs: RCX },
+            Instr::JumpIf(Condition::Above, grow),
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: RDI,
+            },
+            Instr::Ret,
+        ]);
+
+        // grow:
+        // push rdi
+        // push rcx
+        // sub rsp, 8 ; keeping the stack 16 byte aligned
+        // mov rdi, rsi
+        // call alloc
+        // add rsp, 8
+        // pop rdx ; capacity
+        // pop rsi ; old block
+        // cmp rax, 0
+        // je fail
+        // push rsi
+        // mov rdi, rax
+        // call memcpy
+        // pop rdi
+        // push rax
+        // call free
+        // pop rax
+        // fail:
+        // ret
+        self.opt.label(grow);
+        self.emit([
+            Instr::Push(RDI),
+            Instr::Push(RCX),
+            Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RSI,
+            },
+            Instr::Call(LabelId::Runtime(Routine::Alloc)),
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Pop(RDX),
+            Instr::Pop(RSI),
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, fail),
+            Instr::Push(RSI),
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RAX,
+            },
+            Instr::Call(LabelId::Runtime(Routine::Memcpy)),
+            Instr::Pop(RDI),
+            Instr::Push(RAX),
+            Instr::Call(LabelId::Runtime(Routine::Free)),
+            Instr::Pop(RAX),
+        ]);
+        self.opt.label(fail);
+        self.emit([Instr::Ret]);
+    }
+
+    /// Emits `routine` holding the lock of the state it uses, wrapping the unlocked routine
+    fn gen_locked(&mut self, routine: Routine) {
+        let (state, offset) = match routine {
+            Routine::Alloc | Routine::Free => (
+                self.routine_data(Routine::Alloc, HEAP_STATE_SIZE),
+                HEAP_LOCK,
+            ),
+            Routine::Atexit => (
+                self.routine_data(Routine::Atexit, ATEXIT_STATE_SIZE),
+                ATEXIT_LOCK,
+            ),
+            _ => (
+                self.routine_data(Routine::BufferedWrite, OUTPUT_STATE_SIZE),
+                OUTPUT_LOCK,
+            ),
+        };
+        let unlocked = self.new_label();
+        // push rdi
+        // push rsi
+        // push rdx
+        // mov rdi, <state>
+        // add rdi, <lock offset>
+        // call mutex_lock
+        // pop rdx
+        // pop rsi
+        // pop rdi
+        // sub rsp, 8 ; the result, keeping the stack 16 byte aligned
+        // call <unlocked>
+        // mov [rsp], rax
+        // mov rdi, <state>
+        // add rdi, <lock offset>
+        // call mutex_unlock
+        // mov rax, [rsp]
+        // add rsp, 8
+        // ret
+        self.emit([
+            Instr::Push(RDI),
+            Instr::Push(RSI),
+            Instr::Push(RDX),
+            Instr::SetConstant(RDI, Constant::Tbd(state)),
+            Instr::AddConstant {
+                dest: RDI,
+                val: offset,
+            },
+            Instr::Call(LabelId::Runtime(Routine::MutexLock)),
+            Instr::Pop(RDX),
+            Instr::Pop(RSI),
+            Instr::Pop(RDI),
+            Instr::SubConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Call(unlocked),
+            Instr::Store {
+                base: STACK_POINTER,
+                offset: 0,
+                orig: RAX,
+            },
+            Instr::SetConstant(RDI, Constant::Tbd(state)),
+            Instr::AddConstant {
+                dest: RDI,
+                val: offset,
+            },
+            Instr::Call(LabelId::Runtime(Routine::MutexUnlock)),
+            Instr::Load {
+                dest: RAX,
+                base: STACK_POINTER,
+                offset: 0,
+            },
+            Instr::AddConstant {
+                dest: STACK_POINTER,
+                val: 8,
+            },
+            Instr::Ret,
+        ]);
+        self.opt.label(unlocked);
+        self.gen_routine(routine);
+    }
+
+    fn gen_spawn(&mut self) {
+        let fail = self.new_label();
+        let unmap = self.new_label();
+        let child = self.new_label();
+        // push rdi
+        // push rsi
+        // mov rax, THREAD_STACK_SIZE
+        // <mmap rax>
+        // pop rsi
+        // pop rdi
+        // cmp rax, -4096
+        // ja fail
+        // mov r10, rax
+        // add r10, THREAD_BLOCK
+        // mov [r10 + THREAD_FN], rdi
+        // mov [r10 + THREAD_ARG], rsi
+        // mov rdi, rax
+        // mov rsi, PAGE_SIZE
+        // mov rdx, PROT_NONE
+        // mov rax, 10 ; mprotect, making the guard page
+        // syscall
+        // cmp rax, -4096
+        // ja unmap
+        self.emit([
+            Instr::Push(RDI),
+            Instr::Push(RSI),
+            Instr::SetConstant(RAX, Constant::Value(THREAD_STACK_SIZE as u64)),
+        ]);
+        self.gen_mmap(RAX);
+        self.emit([
+            Instr::Pop(RSI),
+            Instr::Pop(RDI),
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::Above, fail),
+            Instr::MoveRegs {
+                dest: R10,
+                orig: RAX,
+            },
+            Instr::AddConstant {
+                dest: R10,
+                val: THREAD_BLOCK,
+            },
+            Instr::Store {
+                base: R10,
+                offset: THREAD_FN,
+                orig: RDI,
+            },
+            Instr::Store {
+                base: R10,
+                offset: THREAD_ARG,
+                orig: RSI,
+            },
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RAX,
+            },
+            Instr::SetConstant(RSI, Constant::Value(PAGE_SIZE)),
+            Instr::SetConstant(RDX, Constant::Value(PROT_NONE)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("mprotect"))),
+            Instr::Syscall,
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: ERRNO_THRESHOLD,
+            },
+            Instr::JumpIf(Condition::Above, unmap),
+        ]);
+
+        // mov rdi, CLONE_THREAD_FLAGS
+        // mov rsi, r10 ; stack, below the control block
+        // mov rdx, r10 ; parent_tid
+        // mov r8, 0 ; tls
+        // mov rax, 56 ; clone, also setting child_tid to r10
+        // syscall
+        // cmp rax, 0
+        // je child
+        // jl unmap
+        // mov rax, r10
+        // ret
+        self.emit([
+            Instr::SetConstant(RDI, Constant::Value(CLONE_THREAD_FLAGS)),
+            Instr::MoveRegs {
+                dest: RSI,
+                orig: R10,
+            },
+            Instr::MoveRegs {
+                dest: RDX,
+                orig: R10,
+            },
+            Instr::SetConstant(R8, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("clone"))),
+            Instr::Syscall,
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, child),
+            Instr::JumpIf(Condition::Less, unmap),
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: R10,
+            },
+            Instr::Ret,
+        ]);
+
+        // child: ; rsp is the control block, 16 byte aligned
+        // mov rdi, [rsp + THREAD_ARG]
+        // mov rax, [rsp + THREAD_FN]
+        // call rax
+        // mov [rsp + THREAD_RESULT], rax
+        // mov rdi, 0
+        // mov rax, 60 ; exit, ending only this thread
+        // syscall
+        self.opt.label(child);
+        self.emit([
+            Instr::Load {
+                dest: RDI,
+                base: STACK_POINTER,
+                offset: THREAD_ARG,
+            },
+            Instr::Load {
+                dest: RAX,
+                base: STACK_POINTER,
+                offset: THREAD_FN,
+            },
+            Instr::CallIndirect(RAX),
+            Instr::Store {
+                base: STACK_POINTER,
+                offset: THREAD_RESULT,
+                orig: RAX,
+            },
+            Instr::SetConstant(RDI, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("exit"))),
+            Instr::Syscall,
+        ]);
+
+        // unmap: ; the stack couldn't be guarded or the thread created
+        // mov rdi, r10
+        // sub rdi, THREAD_BLOCK
+        // mov rsi, THREAD_STACK_SIZE
+        // mov rax, 11 ; munmap
+        // syscall
+        // fail:
+        // mov rax, 0
+        // ret
+        self.opt.label(unmap);
+        self.emit([
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: R10,
+            },
+            Instr::SubConstant {
+                dest: RDI,
+                val: THREAD_BLOCK,
+            },
+            Instr::SetConstant(RSI, Constant::Value(THREAD_STACK_SIZE as u64)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("munmap"))),
+            Instr::Syscall,
+        ]);
+        self.opt.label(fail);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    fn gen_join(&mut self) {
+        let wait_loop = self.new_label();
+        let done = self.new_label();
+        // wait_loop:
+        // mov rdx, [rdi + THREAD_TID]
+        // cmp rdx, 0
+        // je done
+        // mov rsi, FUTEX_WAIT
+        // mov r10, 0 ; no timeout
+        // mov rax, 202 ; futex
+        // syscall
+        // jmp wait_loop
+        self.opt.label(wait_loop);
+        self.emit([
+            Instr::Load {
+                dest: RDX,
+                base: RDI,
+                offset: THREAD_TID,
+            },
+            Instr::CompareConstant { lhs: RDX, val: 0 },
+            Instr::JumpIf(Condition::Equal, done),
+            Instr::SetConstant(RSI, Constant::Value(FUTEX_WAIT)),
+            Instr::SetConstant(R10, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("futex"))),
+            Instr::Syscall,
+            Instr::Jump(wait_loop),
+        ]);
+
+        // done:
+        // mov rdx, [rdi + THREAD_RESULT]
+        // sub rdi, THREAD_BLOCK
+        // mov rsi, THREAD_STACK_SIZE
+        // mov rax, 11 ; munmap
+        // syscall
+        // mov rax, rdx
+        // ret
+        self.opt.label(done);
+        self.emit([
+            Instr::Load {
+                dest: RDX,
+                base: RDI,
+                offset: THREAD_RESULT,
+            },
+            Instr::SubConstant {
+                dest: RDI,
+                val: THREAD_BLOCK,
+            },
+            Instr::SetConstant(RSI, Constant::Value(THREAD_STACK_SIZE as u64)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("munmap"))),
+            Instr::Syscall,
+            Instr::MoveRegs {
+                dest: RAX,
+                orig: RDX,
+            },
+            Instr::Ret,
+        ]);
+    }
+
+    fn gen_mutex_lock(&mut self) {
+        let contended = self.new_label();
+        let locked = self.new_label();
+        // mov rax, 1
+        // xchg [rdi], rax
+        // cmp rax, 0
+        // je locked
+        // contended:
+        // mov rax, 2
+        // xchg [rdi], rax
+        // cmp rax, 0
+        // je locked
+        // mov rsi, FUTEX_WAIT | FUTEX_PRIVATE_FLAG
+        // mov rdx, 2
+        // mov r10, 0 ; no timeout
+        // mov rax, 202 ; futex
+        // syscall
+        // jmp contended
+        // locked:
+        // mov rax, 0
+        // ret
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(MUTEX_LOCKED)),
+            Instr::Exchange {
+                base: RDI,
+                offset: 0,
+                reg: RAX,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, locked),
+        ]);
+        self.opt.label(contended);
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(MUTEX_CONTENDED)),
+            Instr::Exchange {
+                base: RDI,
+                offset: 0,
+                reg: RAX,
+            },
+            Instr::CompareConstant { lhs: RAX, val: 0 },
+            Instr::JumpIf(Condition::Equal, locked),
+            Instr::SetConstant(RSI, Constant::Value(FUTEX_WAIT | FUTEX_PRIVATE_FLAG)),
+            Instr::SetConstant(RDX, Constant::Value(MUTEX_CONTENDED)),
+            Instr::SetConstant(R10, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("futex"))),
+            Instr::Syscall,
+            Instr::Jump(contended),
+        ]);
+        self.opt.label(locked);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    fn gen_mutex_unlock(&mut self) {
+        let done = self.new_label();
+        // mov rax, 0
+        // xchg [rdi], rax
+        // cmp rax, 2
+        // jne done
+        // mov rsi, FUTEX_WAKE | FUTEX_PRIVATE_FLAG
+        // mov rdx, 1
+        // mov rax, 202 ; futex
+        // syscall
+        // done:
+        // mov rax, 0
+        // ret
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::Exchange {
+                base: RDI,
+                offset: 0,
+                reg: RAX,
+            },
+            Instr::CompareConstant {
+                lhs: RAX,
+                val: MUTEX_CONTENDED as i32,
+            },
+            Instr::JumpIf(Condition::NotEqual, done),
+            Instr::SetConstant(RSI, Constant::Value(FUTEX_WAKE | FUTEX_PRIVATE_FLAG)),
+            Instr::SetConstant(RDX, Constant::Value(1)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("futex"))),
+            Instr::Syscall,
+        ]);
+        self.opt.label(done);
+        self.emit([Instr::SetConstant(RAX, Constant::Value(0)), Instr::Ret]);
+    }
+
+    /// Condition variables are a sequence number, bumped by each signal. Waiters sleep until it
+    /// changes from the value read before unlocking the mutex, so no signal is missed
+    fn gen_condvar_wait(&mut self) {
+        // mov rdx, [rdi] ; sequence
+        // push rdi
+        // push rsi
+        // push rdx
+        // mov rdi, rsi
+        // call mutex_unlock
+        // pop rdx
+        // pop r8 ; mutex
+        // pop rdi
+        // mov rsi, FUTEX_WAIT | FUTEX_PRIVATE_FLAG
+        // mov r10, 0 ; no timeout
+        // mov rax, 202 ; futex
+        // syscall
+        // mov rdi, r8
+        // jmp mutex_lock
+        self.emit([
+            Instr::Load {
+                dest: RDX,
+                base: RDI,
+                offset: 0,
+            },
+            Instr::Push(RDI),
+            Instr::Push(RSI),
+            Instr::Push(RDX),
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: RSI,
+            },
+            Instr::Call(LabelId::Runtime(Routine::MutexUnlock)),
+            Instr::Pop(RDX),
+            Instr::Pop(R8),
+            Instr::Pop(RDI),
+            Instr::SetConstant(RSI, Constant::Value(FUTEX_WAIT | FUTEX_PRIVATE_FLAG)),
+            Instr::SetConstant(R10, Constant::Value(0)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("futex"))),
+            Instr::Syscall,
+            Instr::MoveRegs {
+                dest: RDI,
+                orig: R8,
+            },
+            Instr::Jump(LabelId::Runtime(Routine::MutexLock)),
+        ]);
+    }
+
+    /// Bumps the sequence number of a condition variable, waking up to `waiters` threads
+    fn gen_condvar_wake(&mut self, waiters: u64) {
+        // mov rax, 1
+        // lock xadd [rdi], rax
+        // mov rsi, FUTEX_WAKE | FUTEX_PRIVATE_FLAG
+        // mov rdx, <waiters>
+        // mov rax, 202 ; futex
+        // syscall
+        // mov rax, 0
+        // ret
+        self.emit([
+            Instr::SetConstant(RAX, Constant::Value(1)),
+            Instr::AtomicAdd {
+                base: RDI,
+                offset: 0,
+                reg: RAX,
+            },
+            Instr::SetConstant(RSI, Constant::Value(FUTEX_WAKE | FUTEX_PRIVATE_FLAG)),
+            Instr::SetConstant(RDX, Constant::Value(waiters)),
+            Instr::SetConstant(RAX, Constant::Value(syscall_nr("futex"))),
+            Instr::Syscall,
+            Instr::SetConstant(RAX, Constant::Value(0)),
+            Instr::Ret,
+        ]);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::{fs::PermissionsExt, process::ExitStatusExt},
+    path::PathBuf,
+    process::{Command, Output},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::json::{run, Code, JsonSt};
+
+use super::{Entry, Options, X64LinuxElfCodegen};
+
+/// Compiles the program in `examples/<name>` and runs it with `args`
+fn run_example(name: &str, args: &[&str]) -> Output {
+    run_example_with(name, Options::default(), args)
+}
+
+/// Compiles the program in `examples/<name>` with `options` and runs it with `args`
+fn run_example_with(name: &str, options: Options, args: &[&str]) -> Output {
+    let elf = compile_example(name, options);
+    fs::set_permissions(&elf, fs::Permissions::from_mode(0o755)).unwrap();
+    let output = loop {
+        // Another test may still be forking with the executable open for writing
+        match Command::new(&elf).args(args).output() {
+            Err(err) if err.kind() == ErrorKind::ExecutableFileBusy => continue,
+            output => break output.unwrap(),
+        }
+    };
+    fs::remove_file(&elf).unwrap();
+    output
+}
+
+/// Compiles the program in `examples/<name>` with `options`, returning the executable's path
+fn compile_example(name: &str, options: Options) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("examples")
+        .join(name);
+    let code: Code =
+        serde_json::from_str(&fs::read_to_string(dir.join("code.json")).unwrap()).unwrap();
+    let st: JsonSt =
+        serde_json::from_str(&fs::read_to_string(dir.join("st.json")).unwrap()).unwrap();
+    // Tests run in parallel, possibly compiling the same example with different options
+    static BUILT: AtomicUsize = AtomicUsize::new(0);
+    let id = BUILT.fetch_add(1, Ordering::Relaxed);
+    let elf = std::env::temp_dir().join(format!("{name}-{}-{id}.elf", std::process::id()));
+    let mut codegen = X64LinuxElfCodegen::with_options(st, options);
+    codegen.output = elf.clone();
+    run(code, codegen);
+    elf
+}
+
+#[test]
+fn floats() {
+    assert_eq!(run_example("floats", &[]).status.code(), Some(54));
+}
+
+#[test]
+fn scopes() {
+    assert_eq!(run_example("scopes", &[]).status.code(), Some(31));
+}
+
+#[test]
+fn fn_pointers() {
+    assert_eq!(run_example("fn_pointers", &[]).status.code(), Some(49));
+}
+
+#[test]
+fn enums() {
+    assert_eq!(run_example("enums", &[]).status.code(), Some(23));
+}
+
+#[test]
+fn tuples() {
+    assert_eq!(run_example("tuples", &[]).status.code(), Some(156));
+}
+
+#[test]
+fn tail_calls() {
+    assert_eq!(run_example("tail_calls", &[]).status.code(), Some(22));
+}
+
+#[test]
+fn varargs() {
+    assert_eq!(run_example("varargs", &[]).status.code(), Some(22));
+}
+
+#[test]
+fn va_list_forwarding() {
+    assert_eq!(
+        run_example("va_list_forwarding", &[]).status.code(),
+        Some(93)
+    );
+}
+
+#[test]
+fn casts() {
+    assert_eq!(run_example("casts", &[]).status.code(), Some(12));
+}
+
+#[test]
+fn u64_conversions() {
+    assert_eq!(run_example("u64_conversions", &[]).status.code(), Some(63));
+}
+
+#[test]
+fn hello_world() {
+    let output = run_example("helloworld", &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Hello world!\n");
+}
+
+#[test]
+fn data() {
+    assert_eq!(run_example("data", &[]).status.code(), Some(129));
+}
+
+#[test]
+fn goto() {
+    assert_eq!(run_example("goto", &[]).status.code(), Some(130));
+}
+
+#[test]
+fn division() {
+    assert_eq!(run_example("division", &[]).status.code(), Some(17));
+}
+
+#[test]
+fn checked_arithmetic() {
+    let options = Options {
+        checked_arithmetic: true,
+        ..Options::default()
+    };
+    assert_eq!(
+        run_example_with("checked_arithmetic", options, &[])
+            .status
+            .code(),
+        Some(6)
+    );
+}
+
+#[test]
+fn overflow_traps_when_checked() {
+    let options = Options {
+        checked_arithmetic: true,
+        ..Options::default()
+    };
+    let output = run_example_with("overflow", options, &[]);
+    assert_eq!(output.status.code(), Some(136));
+    assert_eq!(output.stderr, b"arithmetic overflow in main\n");
+    assert_eq!(run_example("overflow", &[]).status.code(), Some(0));
+}
+
+#[test]
+fn panic() {
+    let output = run_example("panic", &[]);
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(output.stderr, b"panicked in main: unreachable\n");
+}
+
+#[test]
+fn main_args() {
+    assert_eq!(run_example("args", &["a", "b"]).status.code(), Some(130));
+}
+
+#[test]
+#[should_panic(expected = "main returns F64 instead of an integer")]
+fn float_main() {
+    run_example("float_main", &[]);
+}
+
+#[test]
+fn auxv() {
+    assert_eq!(run_example("auxv", &[]).status.code(), Some(63));
+}
+
+#[test]
+fn runtime() {
+    let output = run_example("runtime", &[]);
+    assert_eq!(output.status.code(), Some(115));
+    assert_eq!(
+        output.stdout,
+        b"hi -1234567\n-9223372036854775808\n18446744073709551615\ndeadbeef\n0\nxxabcdefghix\n"
+    );
+}
+
+#[test]
+fn print_i64() {
+    let output = run_example("print_i64", &[]);
+    assert_eq!(output.status.code(), Some(52));
+    assert_eq!(output.stdout, b"-123442");
+}
+
+#[test]
+fn heap() {
+    assert_eq!(run_example("heap", &[]).status.code(), Some(127));
+}
+
+#[test]
+fn alloc_big() {
+    assert_eq!(run_example("alloc_big", &[]).status.code(), Some(7));
+}
+
+#[test]
+#[should_panic(expected = "Argument 0 of syscall exit_group can't be Ptr")]
+fn syscall_ptr_arg() {
+    run_example("syscall_ptr_arg", &[]);
+}
+
+#[test]
+fn errno() {
+    assert_eq!(run_example("errno", &[]).status.code(), Some(123));
+}
+
+#[test]
+fn mmap() {
+    assert_eq!(run_example("mmap", &[]).status.code(), Some(84));
+}
+
+#[test]
+fn threads() {
+    assert_eq!(run_example("threads", &[]).status.code(), Some(45));
+}
+
+#[test]
+fn threads_exit() {
+    assert_eq!(run_example("threads_exit", &[]).status.code(), Some(7));
+}
+
+#[test]
+fn threaded_print() {
+    let output = run_example("threaded_print", &[]);
+    assert_eq!(output.status.code(), Some(45));
+    assert_eq!(output.stdout, b"xy\n".repeat(40000));
+}
+
+#[test]
+fn buffered_output() {
+    let output = run_example("buffered_output", &[]);
+    assert_eq!(output.status.code(), Some(101));
+    let numbers: String = (0..2000).map(|i| format!("{i}\n")).collect();
+    assert_eq!(
+        output.stdout,
+        format!("{numbers}before flush\nbefore panic\n").as_bytes()
+    );
+    assert_eq!(output.stderr, b"to stderr\npanicked in main: boom\n");
+}
+
+#[test]
+fn panic_flush() {
+    let output = run_example("panic_flush", &[]);
+    assert_eq!(output.status.code(), Some(101));
+    assert_eq!(output.stdout, b"42");
+    assert_eq!(output.stderr, b"panicked in main: boom\n");
+}
+
+#[test]
+fn stack_protector() {
+    let options = Options {
+        stack_protector: true,
+        ..Options::default()
+    };
+    assert_eq!(
+        run_example_with("tuples", options, &[]).status.code(),
+        Some(156)
+    );
+}
+
+#[test]
+fn big_frame() {
+    assert_eq!(run_example("big_frame", &[]).status.code(), Some(200));
+    let options = Options {
+        main_stack_size: Some(64 << 20),
+        ..Options::default()
+    };
+    assert_eq!(
+        run_example_with("big_frame", options, &[]).status.code(),
+        Some(200)
+    );
+    let options = Options {
+        main_stack_size: Some(1 << 20),
+        ..Options::default()
+    };
+    let status = run_example_with("big_frame", options, &[]).status;
+    // The frame runs into the guard page below the stack
+    assert_eq!(status.signal(), Some(11));
+}
+
+#[test]
+fn start() {
+    let options = Options {
+        entry: Entry::Start("_start".to_string()),
+        ..Options::default()
+    };
+    let output = run_example_with("start", options, &[]);
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(output.stdout, b"started\n");
+}
+
+#[test]
+fn no_entry() {
+    let options = Options {
+        entry: Entry::None,
+        ..Options::default()
+    };
+    let elf = compile_example("start", options);
+    // The ELF header's e_entry is left at 0
+    assert_eq!(fs::read(&elf).unwrap()[24..32], [0; 8]);
+    fs::remove_file(&elf).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "The stack protector can't be used without an entry point")]
+fn stack_protector_without_entry() {
+    let options = Options {
+        stack_protector: true,
+        entry: Entry::None,
+        ..Options::default()
+    };
+    let st: JsonSt = serde_json::from_str("[[]]").unwrap();
+    X64LinuxElfCodegen::with_options(st, options);
+}
+
+#[test]
+fn atexit() {
+    let output = run_example("atexit", &[]);
+    assert_eq!(output.status.code(), Some(9));
+    assert_eq!(output.stdout, b"main\nh3\nh2\nh1\n");
+}
+
+#[test]
+fn atexit_on_return() {
+    let output = run_example("atexit_ret", &[]);
+    assert_eq!(output.status.code(), Some(11));
+    assert_eq!(output.stdout, b"main\nh3\nh2\nh1\n");
+}
@@ -1,15 +1,31 @@
 use std::{collections::HashMap, fmt::Debug, hash::Hash};
 
 use crate::codegen::x64_linux_elf::{
-    ir::Constant,
-    x86_64_asm::{mov_const, mov_regs, syscall},
+    ir::{syscall::STACK_POINTER, Constant},
+    x86_64_asm::{
+        add_const, and, call, call_indirect, cmp, cmp_const, cmp_mem, cqo, div, float_cmp,
+        float_load, float_op, float_resize, float_store, float_to_int, idiv, int_op, int_to_float,
+        jcc, jmp, jmp_indirect, lea, load_narrow, lock_xadd, mov_const, mov_load, mov_regs,
+        mov_store, movsd_regs, mul, or, pop, push, ret, set_condition, shr, sign_extend,
+        store_narrow, sub_const, syscall, xchg_mem, zero_extend,
+    },
 };
 
 use super::ir::Instr;
 
+#[derive(Debug, Clone, Copy)]
+enum Relocation {
+    /// 8 byte absolute address
+    Absolute(usize),
+    /// 4 byte absolute value
+    Absolute32(usize),
+    /// 4 byte displacement relative to the end of the field
+    Relative(usize),
+}
+
 pub struct Optimizer<LblId: Eq + Hash> {
     init_addr: u64,
-    relocations: HashMap<LblId, Vec<usize>>,
+    relocations: HashMap<LblId, Vec<Relocation>>,
     labels: HashMap<LblId, u64>,
     code: Vec<u8>,
 }
@@ -30,26 +46,144 @@ impl<LblId: Eq + Hash + Debug> Optimizer<LblId> {
     }
 
     pub fn accept(&mut self, instr: Instr<LblId>) {
-        println!("\t{instr:?}");
         match instr {
             Instr::SetConstant(dest, c) => {
                 let val = match c {
                     Constant::Value(v) => v,
                     Constant::Tbd(lbl) => {
                         let idx = self.get_code_len() + 2;
-                        self.relocations.entry(lbl).or_default().push(idx);
+                        self.relocations
+                            .entry(lbl)
+                            .or_default()
+                            .push(Relocation::Absolute(idx));
                         0
                     }
                 };
                 mov_const(dest, val, &mut self.code).unwrap();
             }
             Instr::MoveRegs { dest, orig } => mov_regs(dest, orig, &mut self.code).unwrap(),
+            Instr::Load { dest, base, offset } => {
+                mov_load(dest, base, offset, &mut self.code).unwrap()
+            }
+            Instr::Store { base, offset, orig } => {
+                mov_store(base, offset, orig, &mut self.code).unwrap()
+            }
+            Instr::Exchange { base, offset, reg } => {
+                xchg_mem(base, offset, reg, &mut self.code).unwrap()
+            }
+            Instr::AtomicAdd { base, offset, reg } => {
+                lock_xadd(base, offset, reg, &mut self.code).unwrap()
+            }
+            Instr::LoadNarrow {
+                size,
+                signed,
+                dest,
+                base,
+                offset,
+            } => load_narrow(size, signed, dest, base, offset, &mut self.code).unwrap(),
+            Instr::StoreNarrow {
+                size,
+                base,
+                offset,
+                orig,
+            } => store_narrow(size, base, offset, orig, &mut self.code).unwrap(),
+            Instr::LoadAddress { dest, base, offset } => {
+                lea(dest, base, offset, &mut self.code).unwrap()
+            }
+            Instr::AddConstant { dest, val } => add_const(dest, val, &mut self.code).unwrap(),
+            Instr::SubConstant { dest, val } => sub_const(dest, val, &mut self.code).unwrap(),
+            Instr::ReserveFrame(lbl) => {
+                // REX.W 81 /5 id, with the immediate after the opcode & ModRM
+                let idx = self.get_code_len() + 3;
+                self.relocations
+                    .entry(lbl)
+                    .or_default()
+                    .push(Relocation::Absolute32(idx));
+                sub_const(STACK_POINTER, 0, &mut self.code).unwrap()
+            }
+            Instr::IntOp { op, dest, orig } => int_op(op, dest, orig, &mut self.code).unwrap(),
+            Instr::SignExtendRax => cqo(&mut self.code).unwrap(),
+            Instr::SignedDiv(divisor) => idiv(divisor, &mut self.code).unwrap(),
+            Instr::UnsignedDiv(divisor) => div(divisor, &mut self.code).unwrap(),
+            Instr::UnsignedMul(factor) => mul(factor, &mut self.code).unwrap(),
+            Instr::SignExtend { size, dest, orig } => {
+                sign_extend(size, dest, orig, &mut self.code).unwrap()
+            }
+            Instr::ZeroExtend { size, dest, orig } => {
+                zero_extend(size, dest, orig, &mut self.code).unwrap()
+            }
+            Instr::Compare { lhs, rhs } => cmp(lhs, rhs, &mut self.code).unwrap(),
+            Instr::CompareConstant { lhs, val } => cmp_const(lhs, val, &mut self.code).unwrap(),
+            Instr::CompareMem { lhs, base, offset } => {
+                cmp_mem(lhs, base, offset, &mut self.code).unwrap()
+            }
+            Instr::SetCondition(cond, dest) => set_condition(cond, dest, &mut self.code).unwrap(),
+            Instr::And { dest, orig } => and(dest, orig, &mut self.code).unwrap(),
+            Instr::Or { dest, orig } => or(dest, orig, &mut self.code).unwrap(),
+            Instr::ShiftRight { dest, amount } => shr(dest, amount, &mut self.code).unwrap(),
+            Instr::MoveXmm { dest, orig } => movsd_regs(dest, orig, &mut self.code).unwrap(),
+            Instr::LoadXmm {
+                size,
+                dest,
+                base,
+                offset,
+            } => float_load(size, dest, base, offset, &mut self.code).unwrap(),
+            Instr::StoreXmm {
+                size,
+                base,
+                offset,
+                orig,
+            } => float_store(size, base, offset, orig, &mut self.code).unwrap(),
+            Instr::FloatOp {
+                op,
+                size,
+                dest,
+                orig,
+            } => float_op(op, size, dest, orig, &mut self.code).unwrap(),
+            Instr::FloatCompare { size, lhs, rhs } => {
+                float_cmp(size, lhs, rhs, &mut self.code).unwrap()
+            }
+            Instr::IntToFloat { size, dest, orig } => {
+                int_to_float(size, dest, orig, &mut self.code).unwrap()
+            }
+            Instr::FloatToInt { size, dest, orig } => {
+                float_to_int(size, dest, orig, &mut self.code).unwrap()
+            }
+            Instr::FloatResize { to, dest, orig } => {
+                float_resize(to, dest, orig, &mut self.code).unwrap()
+            }
             Instr::FreeRegister(_) => (),
+            Instr::FreeXmmRegister => (),
             Instr::Syscall => syscall(&mut self.code).unwrap(),
-            Instr::Push(_) => (), // TODO
-            Instr::Pop(_) => (), // TODO
-            Instr::Ret => (), // TODO
-            Instr::Call(_) => (), // TODO
+            Instr::Push(reg) => push(reg, &mut self.code).unwrap(),
+            Instr::Pop(reg) => pop(reg, &mut self.code).unwrap(),
+            Instr::Ret => ret(&mut self.code).unwrap(),
+            Instr::Call(lbl) => {
+                let idx = self.get_code_len() + 1;
+                self.relocations
+                    .entry(lbl)
+                    .or_default()
+                    .push(Relocation::Relative(idx));
+                call(0, &mut self.code).unwrap()
+            }
+            Instr::CallIndirect(reg) => call_indirect(reg, &mut self.code).unwrap(),
+            Instr::Jump(lbl) => {
+                let idx = self.get_code_len() + 1;
+                self.relocations
+                    .entry(lbl)
+                    .or_default()
+                    .push(Relocation::Relative(idx));
+                jmp(0, &mut self.code).unwrap()
+            }
+            Instr::JumpIndirect(reg) => jmp_indirect(reg, &mut self.code).unwrap(),
+            Instr::JumpIf(cond, lbl) => {
+                let idx = self.get_code_len() + 2;
+                self.relocations
+                    .entry(lbl)
+                    .or_default()
+                    .push(Relocation::Relative(idx));
+                jcc(cond, 0, &mut self.code).unwrap()
+            }
         }
     }
 
@@ -71,14 +205,26 @@ impl<LblId: Eq + Hash + Debug> Optimizer<LblId> {
 
     pub fn apply_relocs(mut self) -> Vec<u8> {
         for (label, addr) in self.labels {
-            println!("Relocating {label:?} with {addr:x}");
-            for code_idx in self
+            for reloc in self
                 .relocations
                 .get(&label)
                 .into_iter()
                 .flat_map(|x| x.iter().copied())
             {
-                self.code[code_idx..(code_idx + 8)].copy_from_slice(&addr.to_ne_bytes());
+                match reloc {
+                    Relocation::Absolute(code_idx) => {
+                        self.code[code_idx..(code_idx + 8)].copy_from_slice(&addr.to_ne_bytes())
+                    }
+                    Relocation::Absolute32(code_idx) => {
+                        let val = u32::try_from(addr).unwrap();
+                        self.code[code_idx..(code_idx + 4)].copy_from_slice(&val.to_le_bytes())
+                    }
+                    Relocation::Relative(code_idx) => {
+                        let next = self.init_addr + code_idx as u64 + 4;
+                        let rel = addr.wrapping_sub(next) as i64 as i32;
+                        self.code[code_idx..(code_idx + 4)].copy_from_slice(&rel.to_le_bytes())
+                    }
+                }
             }
         }
         self.code
@@ -2,7 +2,7 @@ use std::io::Write;
 
 use bitflags::bitflags;
 
-use super::ir::Register;
+use super::ir::{Condition, FloatOp, FloatSize, IntOp, IntSize, Register, XmmRegister};
 
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,23 +18,78 @@ impl Rex {
     const fn as_rex(&self) -> u8 {
         0b0100_0000u8 | self.bits()
     }
+
+    /// Extension bits for a ModRM `reg` field and a ModRM `rm` (or opcode) field
+    const fn for_regs(reg: u8, rm: u8) -> Self {
+        let mut rex = Self::empty();
+        if reg >= 8 {
+            rex = rex.union(Self::R);
+        }
+        if rm >= 8 {
+            rex = rex.union(Self::B);
+        }
+        rex
+    }
+}
+
+const fn mod_rm_regs(reg: u8, rm: u8) -> u8 {
+    0b11_000_000u8 | ((reg & 0b111) << 3) | (rm & 0b111)
+}
+
+/// Writes the optional REX prefix, the opcode and a register-direct ModRM byte.
+/// A REX prefix is always written if `force_rex` is set (needed to address sil, dil, spl & bpl)
+fn encode_regs(
+    rex: Rex,
+    force_rex: bool,
+    opcode: &[u8],
+    reg: u8,
+    rm: u8,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    let rex = rex | Rex::for_regs(reg, rm);
+    if force_rex || !rex.is_empty() {
+        buf.write_all(&[rex.as_rex()])?;
+    }
+    buf.write_all(opcode)?;
+    buf.write_all(&[mod_rm_regs(reg, rm)])
+}
+
+/// Writes the optional REX prefix, the opcode and a `[base + disp32]` ModRM (and SIB) operand.
+/// A REX prefix is always written if `force_rex` is set (needed to address sil, dil, spl & bpl)
+fn encode_mem(
+    rex: Rex,
+    force_rex: bool,
+    opcode: &[u8],
+    reg: u8,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    let rex = rex | Rex::for_regs(reg, base.0);
+    if force_rex || !rex.is_empty() {
+        buf.write_all(&[rex.as_rex()])?;
+    }
+    buf.write_all(opcode)?;
+    buf.write_all(&[0b10_000_000u8 | ((reg & 0b111) << 3) | (base.0 & 0b111)])?;
+    if base.0 & 0b111 == 0b100 {
+        // rsp & r12 as base need a SIB byte: no index, base
+        buf.write_all(&[0b00_100_100])?;
+    }
+    buf.write_all(&offset.to_le_bytes())
+}
+
+const fn float_prefix(size: FloatSize) -> u8 {
+    match size {
+        FloatSize::Single => 0xF3,
+        FloatSize::Double => 0xF2,
+    }
 }
 
 pub fn mov_regs(dest: Register, origin: Register, buf: &mut impl Write) -> std::io::Result<()> {
     // REX.W + 89 /r
     // MOV r/m64, r64
     // Move r64 to r/m64
-    let mut rex = Rex::W;
-    if dest.0 >= 8 {
-        rex |= Rex::R
-    }
-    if origin.0 >= 8 {
-        rex |= Rex::B
-    }
-    let opcode = 0x89u8;
-    let mod_rm = 0b11_000_000u8 | ((origin.0 & 0b111) << 3) | (dest.0 & 0b111);
-    buf.write_all(&[rex.as_rex(), opcode, mod_rm])?;
-    Ok(())
+    encode_regs(Rex::W, false, &[0x89], origin.0, dest.0, buf)
 }
 
 /// imm64 offset to start: 2 bytes
@@ -44,7 +99,7 @@ pub fn mov_const(dest: Register, origin: u64, buf: &mut impl Write) -> std::io::
     // Move imm64 to r64.
     let mut rex = Rex::W;
     if dest.0 >= 8 {
-        rex |= Rex::R
+        rex |= Rex::B
     }
     let opcode = 0xB8_u8 | (dest.0 & 0b111);
     buf.write_all(&[rex.as_rex(), opcode])?;
@@ -52,9 +107,730 @@ pub fn mov_const(dest: Register, origin: u64, buf: &mut impl Write) -> std::io::
     Ok(())
 }
 
+pub fn mov_load(
+    dest: Register,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // REX.W + 8B /r
+    // MOV r64, r/m64
+    encode_mem(Rex::W, false, &[0x8B], dest.0, base, offset, buf)
+}
+
+pub fn mov_store(
+    base: Register,
+    offset: i32,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // REX.W + 89 /r
+    // MOV r/m64, r64
+    encode_mem(Rex::W, false, &[0x89], origin.0, base, offset, buf)
+}
+
+pub fn xchg_mem(
+    base: Register,
+    offset: i32,
+    reg: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // REX.W + 87 /r
+    // XCHG r/m64, r64
+    // Exchange r64 with quadword from r/m64, locked as a memory operand is used
+    encode_mem(Rex::W, false, &[0x87], reg.0, base, offset, buf)
+}
+
+pub fn lock_xadd(
+    base: Register,
+    offset: i32,
+    reg: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F0 REX.W + 0F C1 /r
+    // LOCK XADD r/m64, r64
+    // Exchange r64 and r/m64; load sum into r/m64, atomically
+    buf.write_all(&[0xF0])?;
+    encode_mem(Rex::W, false, &[0x0F, 0xC1], reg.0, base, offset, buf)
+}
+
+pub fn load_narrow(
+    size: IntSize,
+    signed: bool,
+    dest: Register,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    let (rex, opcode): (Rex, &[u8]) = match (size, signed) {
+        // REX.W + 0F BE /r
+        // MOVSX r64, r/m8
+        (IntSize::Byte, true) => (Rex::W, &[0x0F, 0xBE]),
+        // REX.W + 0F B6 /r
+        // MOVZX r64, r/m8
+        (IntSize::Byte, false) => (Rex::W, &[0x0F, 0xB6]),
+        // REX.W + 0F BF /r
+        // MOVSX r64, r/m16
+        (IntSize::Word, true) => (Rex::W, &[0x0F, 0xBF]),
+        // REX.W + 0F B7 /r
+        // MOVZX r64, r/m16
+        (IntSize::Word, false) => (Rex::W, &[0x0F, 0xB7]),
+        // REX.W + 63 /r
+        // MOVSXD r64, r/m32
+        (IntSize::Dword, true) => (Rex::W, &[0x63]),
+        // 8B /r
+        // MOV r32, r/m32
+        // The upper 32 bits of the 64 bit register are zeroed.
+        (IntSize::Dword, false) => (Rex::empty(), &[0x8B]),
+    };
+    encode_mem(rex, false, opcode, dest.0, base, offset, buf)
+}
+
+pub fn store_narrow(
+    size: IntSize,
+    base: Register,
+    offset: i32,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    match size {
+        // 88 /r
+        // MOV r/m8, r8
+        IntSize::Byte => encode_mem(Rex::empty(), true, &[0x88], origin.0, base, offset, buf),
+        // 66 89 /r
+        // MOV r/m16, r16
+        IntSize::Word => {
+            buf.write_all(&[0x66])?;
+            encode_mem(Rex::empty(), false, &[0x89], origin.0, base, offset, buf)
+        }
+        // 89 /r
+        // MOV r/m32, r32
+        IntSize::Dword => encode_mem(Rex::empty(), false, &[0x89], origin.0, base, offset, buf),
+    }
+}
+
+pub fn lea(
+    dest: Register,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // REX.W + 8D /r
+    // LEA r64, m
+    encode_mem(Rex::W, false, &[0x8D], dest.0, base, offset, buf)
+}
+
+pub fn add_const(dest: Register, val: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 81 /0 id
+    // ADD r/m64, imm32
+    encode_regs(Rex::W, false, &[0x81], 0, dest.0, buf)?;
+    buf.write_all(&val.to_le_bytes())
+}
+
+pub fn sub_const(dest: Register, val: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 81 /5 id
+    // SUB r/m64, imm32
+    encode_regs(Rex::W, false, &[0x81], 5, dest.0, buf)?;
+    buf.write_all(&val.to_le_bytes())
+}
+
+pub fn int_op(
+    op: IntOp,
+    dest: Register,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    match op {
+        // REX.W + 01 /r
+        // ADD r/m64, r64
+        IntOp::Add => encode_regs(Rex::W, false, &[0x01], origin.0, dest.0, buf),
+        // REX.W + 29 /r
+        // SUB r/m64, r64
+        IntOp::Sub => encode_regs(Rex::W, false, &[0x29], origin.0, dest.0, buf),
+        // REX.W + 0F AF /r
+        // IMUL r64, r/m64
+        IntOp::Mul => encode_regs(Rex::W, false, &[0x0F, 0xAF], dest.0, origin.0, buf),
+    }
+}
+
+pub fn and(dest: Register, origin: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 21 /r
+    // AND r/m64, r64
+    encode_regs(Rex::W, false, &[0x21], origin.0, dest.0, buf)
+}
+
+pub fn or(dest: Register, origin: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 09 /r
+    // OR r/m64, r64
+    encode_regs(Rex::W, false, &[0x09], origin.0, dest.0, buf)
+}
+
+pub fn shr(dest: Register, amount: u8, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + C1 /5 ib
+    // SHR r/m64, imm8
+    // Unsigned divide r/m64 by 2, imm8 times.
+    encode_regs(Rex::W, false, &[0xC1], 5, dest.0, buf)?;
+    buf.write_all(&[amount])
+}
+
+pub fn cqo(buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 99
+    // CQO
+    // RDX:RAX := sign-extend of RAX.
+    buf.write_all(&[Rex::W.as_rex(), 0x99])
+}
+
+pub fn idiv(divisor: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + F7 /7
+    // IDIV r/m64
+    // Signed divide RDX:RAX by r/m64, with result stored in RAX := Quotient, RDX := Remainder.
+    encode_regs(Rex::W, false, &[0xF7], 7, divisor.0, buf)
+}
+
+pub fn div(divisor: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + F7 /6
+    // DIV r/m64
+    // Unsigned divide RDX:RAX by r/m64, with result stored in RAX := Quotient, RDX := Remainder.
+    encode_regs(Rex::W, false, &[0xF7], 6, divisor.0, buf)
+}
+
+pub fn mul(factor: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + F7 /4
+    // MUL r/m64
+    // Unsigned multiply (RDX:RAX := RAX * r/m64).
+    encode_regs(Rex::W, false, &[0xF7], 4, factor.0, buf)
+}
+
+pub fn sign_extend(
+    size: IntSize,
+    dest: Register,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    match size {
+        // REX.W + 0F BE /r
+        // MOVSX r64, r/m8
+        IntSize::Byte => encode_regs(Rex::W, false, &[0x0F, 0xBE], dest.0, origin.0, buf),
+        // REX.W + 0F BF /r
+        // MOVSX r64, r/m16
+        IntSize::Word => encode_regs(Rex::W, false, &[0x0F, 0xBF], dest.0, origin.0, buf),
+        // REX.W + 63 /r
+        // MOVSXD r64, r/m32
+        IntSize::Dword => encode_regs(Rex::W, false, &[0x63], dest.0, origin.0, buf),
+    }
+}
+
+pub fn zero_extend(
+    size: IntSize,
+    dest: Register,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    match size {
+        // REX.W + 0F B6 /r
+        // MOVZX r64, r/m8
+        IntSize::Byte => encode_regs(Rex::W, false, &[0x0F, 0xB6], dest.0, origin.0, buf),
+        // REX.W + 0F B7 /r
+        // MOVZX r64, r/m16
+        IntSize::Word => encode_regs(Rex::W, false, &[0x0F, 0xB7], dest.0, origin.0, buf),
+        // 8B /r
+        // MOV r32, r/m32
+        // The upper 32 bits of the 64 bit register are zeroed.
+        IntSize::Dword => encode_regs(Rex::empty(), false, &[0x8B], dest.0, origin.0, buf),
+    }
+}
+
+pub fn cmp(lhs: Register, rhs: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 39 /r
+    // CMP r/m64, r64
+    encode_regs(Rex::W, false, &[0x39], rhs.0, lhs.0, buf)
+}
+
+pub fn cmp_mem(
+    lhs: Register,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // REX.W + 3B /r
+    // CMP r64, r/m64
+    encode_mem(Rex::W, false, &[0x3B], lhs.0, base, offset, buf)
+}
+
+pub fn cmp_const(lhs: Register, val: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // REX.W + 81 /7 id
+    // CMP r/m64, imm32
+    encode_regs(Rex::W, false, &[0x81], 7, lhs.0, buf)?;
+    buf.write_all(&val.to_le_bytes())
+}
+
+pub fn set_condition(cond: Condition, dest: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // 0F 90+cc /0
+    // SETcc r/m8
+    encode_regs(
+        Rex::empty(),
+        true,
+        &[0x0F, 0x90 | cond as u8],
+        0,
+        dest.0,
+        buf,
+    )?;
+    // REX.W + 0F B6 /r
+    // MOVZX r64, r/m8
+    encode_regs(Rex::W, false, &[0x0F, 0xB6], dest.0, dest.0, buf)
+}
+
+pub fn movsd_regs(
+    dest: XmmRegister,
+    origin: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 0F 10 /r
+    // MOVSD xmm1, xmm2
+    // Moving the whole low quadword is valid for single precision values too
+    buf.write_all(&[0xF2])?;
+    encode_regs(Rex::empty(), false, &[0x0F, 0x10], dest.0, origin.0, buf)
+}
+
+pub fn float_load(
+    size: FloatSize,
+    dest: XmmRegister,
+    base: Register,
+    offset: i32,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 0F 10 /r
+    // MOVSD xmm1, m64
+    // F3 0F 10 /r
+    // MOVSS xmm1, m32
+    buf.write_all(&[float_prefix(size)])?;
+    encode_mem(
+        Rex::empty(),
+        false,
+        &[0x0F, 0x10],
+        dest.0,
+        base,
+        offset,
+        buf,
+    )
+}
+
+pub fn float_store(
+    size: FloatSize,
+    base: Register,
+    offset: i32,
+    origin: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 0F 11 /r
+    // MOVSD m64, xmm1
+    // F3 0F 11 /r
+    // MOVSS m32, xmm1
+    buf.write_all(&[float_prefix(size)])?;
+    encode_mem(
+        Rex::empty(),
+        false,
+        &[0x0F, 0x11],
+        origin.0,
+        base,
+        offset,
+        buf,
+    )
+}
+
+pub fn float_op(
+    op: FloatOp,
+    size: FloatSize,
+    dest: XmmRegister,
+    origin: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 0F 58 /r ADDSD | F2 0F 5C /r SUBSD | F2 0F 59 /r MULSD | F2 0F 5E /r DIVSD
+    // F3 prefix for the single precision (SS) versions
+    let opcode = match op {
+        FloatOp::Add => 0x58,
+        FloatOp::Sub => 0x5C,
+        FloatOp::Mul => 0x59,
+        FloatOp::Div => 0x5E,
+    };
+    buf.write_all(&[float_prefix(size)])?;
+    encode_regs(Rex::empty(), false, &[0x0F, opcode], dest.0, origin.0, buf)
+}
+
+pub fn float_cmp(
+    size: FloatSize,
+    lhs: XmmRegister,
+    rhs: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // 66 0F 2E /r
+    // UCOMISD xmm1, xmm2/m64
+    // NP 0F 2E /r
+    // UCOMISS xmm1, xmm2/m32
+    if size == FloatSize::Double {
+        buf.write_all(&[0x66])?;
+    }
+    encode_regs(Rex::empty(), false, &[0x0F, 0x2E], lhs.0, rhs.0, buf)
+}
+
+pub fn int_to_float(
+    size: FloatSize,
+    dest: XmmRegister,
+    origin: Register,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 REX.W 0F 2A /r
+    // CVTSI2SD xmm1, r/m64
+    // F3 REX.W 0F 2A /r
+    // CVTSI2SS xmm1, r/m64
+    buf.write_all(&[float_prefix(size)])?;
+    encode_regs(Rex::W, false, &[0x0F, 0x2A], dest.0, origin.0, buf)
+}
+
+pub fn float_to_int(
+    size: FloatSize,
+    dest: Register,
+    origin: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F2 REX.W 0F 2C /r
+    // CVTTSD2SI r64, xmm1/m64
+    // F3 REX.W 0F 2C /r
+    // CVTTSS2SI r64, xmm1/m32
+    buf.write_all(&[float_prefix(size)])?;
+    encode_regs(Rex::W, false, &[0x0F, 0x2C], dest.0, origin.0, buf)
+}
+
+pub fn float_resize(
+    to: FloatSize,
+    dest: XmmRegister,
+    origin: XmmRegister,
+    buf: &mut impl Write,
+) -> std::io::Result<()> {
+    // F3 0F 5A /r
+    // CVTSS2SD xmm1, xmm2/m32
+    // F2 0F 5A /r
+    // CVTSD2SS xmm1, xmm2/m64
+    let prefix = match to {
+        FloatSize::Double => 0xF3,
+        FloatSize::Single => 0xF2,
+    };
+    buf.write_all(&[prefix])?;
+    encode_regs(Rex::empty(), false, &[0x0F, 0x5A], dest.0, origin.0, buf)
+}
+
+pub fn push(reg: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // 50+rd
+    // PUSH r64
+    if reg.0 >= 8 {
+        buf.write_all(&[Rex::B.as_rex()])?;
+    }
+    buf.write_all(&[0x50 | (reg.0 & 0b111)])
+}
+
+pub fn pop(reg: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // 58+rd
+    // POP r64
+    if reg.0 >= 8 {
+        buf.write_all(&[Rex::B.as_rex()])?;
+    }
+    buf.write_all(&[0x58 | (reg.0 & 0b111)])
+}
+
+pub fn ret(buf: &mut impl Write) -> std::io::Result<()> {
+    // C3
+    // RET
+    // Near return to calling procedure.
+    buf.write_all(&[0xC3])
+}
+
+/// rel32 offset to start: 1 byte
+pub fn call(rel: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // E8 cd
+    // CALL rel32
+    // Call near, relative, displacement relative to next instruction.
+    buf.write_all(&[0xE8])?;
+    buf.write_all(&rel.to_le_bytes())
+}
+
+pub fn call_indirect(target: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // FF /2
+    // CALL r/m64
+    // Call near, absolute indirect, address given in r/m64.
+    encode_regs(Rex::empty(), false, &[0xFF], 2, target.0, buf)
+}
+
+/// rel32 offset to start: 1 byte
+pub fn jmp(rel: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // E9 cd
+    // JMP rel32
+    buf.write_all(&[0xE9])?;
+    buf.write_all(&rel.to_le_bytes())
+}
+
+pub fn jmp_indirect(target: Register, buf: &mut impl Write) -> std::io::Result<()> {
+    // FF /4
+    // JMP r/m64
+    // Jump near, absolute indirect, address given in r/m64.
+    encode_regs(Rex::empty(), false, &[0xFF], 4, target.0, buf)
+}
+
+/// rel32 offset to start: 2 bytes
+pub fn jcc(cond: Condition, rel: i32, buf: &mut impl Write) -> std::io::Result<()> {
+    // 0F 80+cc cd
+    // Jcc rel32
+    buf.write_all(&[0x0F, 0x80 | cond as u8])?;
+    buf.write_all(&rel.to_le_bytes())
+}
+
 pub fn syscall(buf: &mut impl Write) -> std::io::Result<()> {
     // 0F 05
     // SYSCALL
     // Fast call to privilege level 0 system procedures.
     buf.write_all(&[0x0F, 0x05])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAX: Register = Register(0);
+    const RCX: Register = Register(1);
+    const RBX: Register = Register(3);
+    const RSP: Register = Register(4);
+    const RBP: Register = Register(5);
+    const RSI: Register = Register(6);
+    const RDI: Register = Register(7);
+    const R8: Register = Register(8);
+    const R9: Register = Register(9);
+    const R10: Register = Register(10);
+    const R11: Register = Register(11);
+    const R12: Register = Register(12);
+    const R15: Register = Register(15);
+    const XMM0: XmmRegister = XmmRegister(0);
+    const XMM1: XmmRegister = XmmRegister(1);
+    const XMM9: XmmRegister = XmmRegister(9);
+
+    fn encode(f: impl FnOnce(&mut Vec<u8>) -> std::io::Result<()>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        f(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn moves() {
+        assert_eq!(encode(|b| mov_regs(RAX, RBX, b)), [0x48, 0x89, 0xD8]);
+        assert_eq!(encode(|b| mov_regs(R9, RAX, b)), [0x49, 0x89, 0xC1]);
+        assert_eq!(
+            encode(|b| mov_const(RAX, 0x1122_3344_5566_7788, b)),
+            [0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+        assert_eq!(
+            encode(|b| mov_const(R10, 1, b)),
+            [0x49, 0xBA, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn memory_operands() {
+        assert_eq!(
+            encode(|b| mov_load(RAX, RBP, -8, b)),
+            [0x48, 0x8B, 0x85, 0xF8, 0xFF, 0xFF, 0xFF]
+        );
+        // rsp & r12 as base need a SIB byte
+        assert_eq!(
+            encode(|b| mov_load(RAX, RSP, 16, b)),
+            [0x48, 0x8B, 0x84, 0x24, 0x10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| mov_store(R12, 0, RCX, b)),
+            [0x49, 0x89, 0x8C, 0x24, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| lea(RDI, RSP, 8, b)),
+            [0x48, 0x8D, 0xBC, 0x24, 8, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| cmp_mem(RAX, RBP, -16, b)),
+            [0x48, 0x3B, 0x85, 0xF0, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn atomics() {
+        assert_eq!(
+            encode(|b| xchg_mem(RBX, 0, RAX, b)),
+            [0x48, 0x87, 0x83, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| lock_xadd(RDI, 8, RAX, b)),
+            [0xF0, 0x48, 0x0F, 0xC1, 0x87, 8, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn narrow_memory_operands() {
+        assert_eq!(
+            encode(|b| load_narrow(IntSize::Byte, true, RAX, RBP, -1, b)),
+            [0x48, 0x0F, 0xBE, 0x85, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            encode(|b| load_narrow(IntSize::Dword, true, RAX, RBP, -4, b)),
+            [0x48, 0x63, 0x85, 0xFC, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            encode(|b| load_narrow(IntSize::Dword, false, RAX, RBP, -4, b)),
+            [0x8B, 0x85, 0xFC, 0xFF, 0xFF, 0xFF]
+        );
+        // sil needs a REX prefix, even an empty one
+        assert_eq!(
+            encode(|b| store_narrow(IntSize::Byte, RAX, 0, RSI, b)),
+            [0x40, 0x88, 0xB0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| store_narrow(IntSize::Word, RBP, -2, RAX, b)),
+            [0x66, 0x89, 0x85, 0xFE, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            encode(|b| add_const(RSP, 16, b)),
+            [0x48, 0x81, 0xC4, 0x10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| sub_const(RSP, 16, b)),
+            [0x48, 0x81, 0xEC, 0x10, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| int_op(IntOp::Add, RAX, RCX, b)),
+            [0x48, 0x01, 0xC8]
+        );
+        assert_eq!(
+            encode(|b| int_op(IntOp::Sub, RAX, RCX, b)),
+            [0x48, 0x29, 0xC8]
+        );
+        assert_eq!(
+            encode(|b| int_op(IntOp::Mul, RAX, RCX, b)),
+            [0x48, 0x0F, 0xAF, 0xC1]
+        );
+        assert_eq!(encode(|b| and(RAX, RCX, b)), [0x48, 0x21, 0xC8]);
+        assert_eq!(encode(|b| or(RAX, RCX, b)), [0x48, 0x09, 0xC8]);
+        assert_eq!(encode(cqo), [0x48, 0x99]);
+        assert_eq!(encode(|b| idiv(RCX, b)), [0x48, 0xF7, 0xF9]);
+        assert_eq!(encode(|b| div(RCX, b)), [0x48, 0xF7, 0xF1]);
+        assert_eq!(encode(|b| mul(RCX, b)), [0x48, 0xF7, 0xE1]);
+        assert_eq!(encode(|b| shr(RAX, 1, b)), [0x48, 0xC1, 0xE8, 1]);
+        assert_eq!(encode(|b| shr(R9, 63, b)), [0x49, 0xC1, 0xE9, 63]);
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(
+            encode(|b| sign_extend(IntSize::Byte, RAX, RCX, b)),
+            [0x48, 0x0F, 0xBE, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| sign_extend(IntSize::Dword, RAX, RCX, b)),
+            [0x48, 0x63, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| zero_extend(IntSize::Byte, R8, RSI, b)),
+            [0x4C, 0x0F, 0xB6, 0xC6]
+        );
+        assert_eq!(
+            encode(|b| zero_extend(IntSize::Dword, RAX, RCX, b)),
+            [0x8B, 0xC1]
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(encode(|b| cmp(RAX, RCX, b)), [0x48, 0x39, 0xC8]);
+        assert_eq!(
+            encode(|b| cmp_const(RAX, 5, b)),
+            [0x48, 0x81, 0xF8, 5, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| set_condition(Condition::Equal, RAX, b)),
+            [0x40, 0x0F, 0x94, 0xC0, 0x48, 0x0F, 0xB6, 0xC0]
+        );
+        assert_eq!(
+            encode(|b| set_condition(Condition::Less, RDI, b)),
+            [0x40, 0x0F, 0x9C, 0xC7, 0x48, 0x0F, 0xB6, 0xFF]
+        );
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(
+            encode(|b| movsd_regs(XMM1, XMM0, b)),
+            [0xF2, 0x0F, 0x10, 0xC8]
+        );
+        assert_eq!(
+            encode(|b| float_load(FloatSize::Double, XMM0, RBP, -8, b)),
+            [0xF2, 0x0F, 0x10, 0x85, 0xF8, 0xFF, 0xFF, 0xFF]
+        );
+        // The mandatory prefix goes before REX
+        assert_eq!(
+            encode(|b| float_store(FloatSize::Single, RSP, 0, XMM9, b)),
+            [0xF3, 0x44, 0x0F, 0x11, 0x8C, 0x24, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            encode(|b| float_op(FloatOp::Add, FloatSize::Double, XMM0, XMM1, b)),
+            [0xF2, 0x0F, 0x58, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| float_op(FloatOp::Div, FloatSize::Single, XMM0, XMM1, b)),
+            [0xF3, 0x0F, 0x5E, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| float_cmp(FloatSize::Double, XMM0, XMM1, b)),
+            [0x66, 0x0F, 0x2E, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| float_cmp(FloatSize::Single, XMM0, XMM1, b)),
+            [0x0F, 0x2E, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| int_to_float(FloatSize::Double, XMM0, RAX, b)),
+            [0xF2, 0x48, 0x0F, 0x2A, 0xC0]
+        );
+        assert_eq!(
+            encode(|b| float_to_int(FloatSize::Single, RAX, XMM1, b)),
+            [0xF3, 0x48, 0x0F, 0x2C, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| float_resize(FloatSize::Double, XMM0, XMM1, b)),
+            [0xF3, 0x0F, 0x5A, 0xC1]
+        );
+        assert_eq!(
+            encode(|b| float_resize(FloatSize::Single, XMM0, XMM1, b)),
+            [0xF2, 0x0F, 0x5A, 0xC1]
+        );
+    }
+
+    #[test]
+    fn stack() {
+        assert_eq!(encode(|b| push(RBP, b)), [0x55]);
+        assert_eq!(encode(|b| push(R12, b)), [0x41, 0x54]);
+        assert_eq!(encode(|b| pop(RBX, b)), [0x5B]);
+        assert_eq!(encode(|b| pop(R15, b)), [0x41, 0x5F]);
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_eq!(encode(ret), [0xC3]);
+        assert_eq!(encode(syscall), [0x0F, 0x05]);
+        assert_eq!(encode(|b| call(-5, b)), [0xE8, 0xFB, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(encode(|b| jmp(0x10, b)), [0xE9, 0x10, 0, 0, 0]);
+        assert_eq!(
+            encode(|b| jcc(Condition::NotEqual, 0x20, b)),
+            [0x0F, 0x85, 0x20, 0, 0, 0]
+        );
+        assert_eq!(encode(|b| call_indirect(RAX, b)), [0xFF, 0xD0]);
+        assert_eq!(encode(|b| call_indirect(R11, b)), [0x41, 0xFF, 0xD3]);
+        assert_eq!(encode(|b| jmp_indirect(RAX, b)), [0xFF, 0xE0]);
+    }
+}
@@ -2,6 +2,19 @@ pub trait St {
     type StEntryId: Clone + Copy;
 
     fn get(&self, idx: Self::StEntryId) -> &StEntry;
+
+    /// Entries of the `st`th symbol table, in declaration order
+    fn entries(&self, st: usize) -> Vec<Self::StEntryId>;
+
+    /// Index of the symbol table containing the entry
+    fn st_of(&self, idx: Self::StEntryId) -> usize;
+
+    /// Looks up `lexeme` in the `st`th symbol table only, without going to enclosing scopes
+    fn find(&self, st: usize, lexeme: &str) -> Option<Self::StEntryId> {
+        self.entries(st)
+            .into_iter()
+            .find(|&id| self.get(id).lexeme == lexeme)
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -16,13 +29,112 @@ pub struct StEntry {
 #[serde(rename_all = "snake_case")]
 pub enum StEntryKind {
     Function(FunctionStEntry),
+    Variable(VariableStEntry),
+    Enum(EnumStEntry),
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct FunctionStEntry {
-    pub args: Vec<String>, // TODO Type
-    pub ret: String,
+    pub args: Vec<Type>,
+    pub ret: Type,
     pub inner_st: usize,
+    /// Takes further arguments after `args`, read through a [`Type::VaList`]
+    #[serde(default)]
+    pub variadic: bool,
+}
+
+/// A local variable, living in a stack slot for as long as its scope is active
+#[derive(Debug, serde::Deserialize)]
+pub struct VariableStEntry {
+    pub ty: Type,
+}
+
+/// A tagged union.
+///
+/// Values are laid out in memory as a `u64` tag, the index of the variant in `variants`,
+/// followed by the fields of the variant in order, each in its own 8 byte slot (floats use the
+/// low bytes of theirs). All values of the enum have the size of its largest variant,
+/// `8 * (1 + max fields)` bytes, and are 8 byte aligned.
+#[derive(Debug, serde::Deserialize)]
+pub struct EnumStEntry {
+    pub variants: Vec<EnumVariant>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct EnumVariant {
+    pub name: String,
+    #[serde(default)]
+    pub fields: Vec<Type>,
+}
+
+impl EnumStEntry {
+    /// Size in bytes of every value of the enum
+    pub fn size(&self) -> u64 {
+        let fields = self.variants.iter().map(|v| v.fields.len()).max();
+        8 * (1 + fields.unwrap_or(0) as u64)
+    }
+
+    /// The tag and definition of the variant named `name`
+    pub fn variant(&self, name: &str) -> Option<(u64, &EnumVariant)> {
+        self.variants
+            .iter()
+            .enumerate()
+            .find(|(_, v)| v.name == name)
+            .map(|(tag, v)| (tag as u64, v))
+    }
+}
+
+#[derive(Debug, serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Type {
+    #[serde(alias = "int")]
+    I64,
+    I8,
+    I16,
+    I32,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    /// An address, of data or of a function
+    Ptr,
+    /// A value of the enum with the given name, handled through its address
+    Enum(String),
+    /// A fixed sequence of scalars, laid out in consecutive 8 byte slots and handled through its
+    /// address like enums
+    Tuple(Vec<Type>),
+    /// Position in the variadic arguments of a function, laid out as the SysV `va_list`. Like
+    /// the array it is in C, it's passed to calls by address
+    VaList,
+}
+
+impl Type {
+    pub const fn is_float(&self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+
+    /// Width in bits and signedness of integer types, pointers being unsigned 64 bit integers.
+    /// Narrower integers are kept sign or zero extended to 64 bits in registers and slots
+    pub const fn int_repr(&self) -> Option<(u32, bool)> {
+        match self {
+            Self::I8 => Some((8, true)),
+            Self::I16 => Some((16, true)),
+            Self::I32 => Some((32, true)),
+            Self::I64 => Some((64, true)),
+            Self::U8 => Some((8, false)),
+            Self::U16 => Some((16, false)),
+            Self::U32 => Some((32, false)),
+            Self::U64 | Self::Ptr => Some((64, false)),
+            _ => None,
+        }
+    }
+
+    /// Whether values of this type fit in a single register
+    pub const fn is_scalar(&self) -> bool {
+        !matches!(self, Self::Enum(_) | Self::Tuple(_) | Self::VaList)
+    }
 }
 
 impl StEntryKind {
@@ -32,4 +144,79 @@ impl StEntryKind {
             _ => None,
         }
     }
+
+    pub const fn unwrap_variable(&self) -> Option<&VariableStEntry> {
+        match self {
+            Self::Variable(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub const fn unwrap_enum(&self) -> Option<&EnumStEntry> {
+        match self {
+            Self::Enum(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(name: &str, fields: Vec<Type>) -> EnumVariant {
+        EnumVariant {
+            name: name.to_string(),
+            fields,
+        }
+    }
+
+    #[test]
+    fn enum_layout() {
+        let option = EnumStEntry {
+            variants: vec![
+                variant("none", vec![]),
+                variant("some", vec![Type::I64]),
+                variant("pair", vec![Type::F64, Type::Ptr]),
+            ],
+        };
+        assert_eq!(option.size(), 24);
+        assert_eq!(option.variant("none").unwrap().0, 0);
+        assert_eq!(option.variant("pair").unwrap().0, 2);
+        assert_eq!(option.variant("pair").unwrap().1.fields.len(), 2);
+        assert!(option.variant("other").is_none());
+
+        let unit = EnumStEntry {
+            variants: vec![variant("a", vec![]), variant("b", vec![])],
+        };
+        assert_eq!(unit.size(), 8);
+        assert_eq!(EnumStEntry { variants: vec![] }.size(), 8);
+    }
+
+    #[test]
+    fn scalars() {
+        for ty in [Type::I64, Type::U8, Type::F32, Type::Ptr] {
+            assert!(ty.is_scalar(), "{ty:?}");
+        }
+        for ty in [
+            Type::Enum("e".into()),
+            Type::Tuple(vec![Type::I64]),
+            Type::VaList,
+        ] {
+            assert!(!ty.is_scalar(), "{ty:?}");
+        }
+    }
+
+    #[test]
+    fn int_representations() {
+        assert_eq!(Type::I8.int_repr(), Some((8, true)));
+        assert_eq!(Type::I32.int_repr(), Some((32, true)));
+        assert_eq!(Type::I64.int_repr(), Some((64, true)));
+        assert_eq!(Type::U16.int_repr(), Some((16, false)));
+        assert_eq!(Type::U64.int_repr(), Some((64, false)));
+        assert_eq!(Type::Ptr.int_repr(), Some((64, false)));
+        assert_eq!(Type::F64.int_repr(), None);
+        assert_eq!(Type::Enum("e".into()).int_repr(), None);
+        assert_eq!(Type::VaList.int_repr(), None);
+    }
 }
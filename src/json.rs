@@ -1,6 +1,6 @@
 use crate::{
     codegen::generic::Codegen,
-    data::{St, StEntry},
+    data::{St, StEntry, Type},
 };
 
 pub type JsonSt = Vec<Vec<StEntry>>;
@@ -17,6 +17,16 @@ impl St for JsonSt {
     fn get(&self, idx: Self::StEntryId) -> &StEntry {
         &self[idx.st_idx][idx.idx]
     }
+
+    fn entries(&self, st: usize) -> Vec<Self::StEntryId> {
+        (0..self[st].len())
+            .map(|idx| StEntryRef { st_idx: st, idx })
+            .collect()
+    }
+
+    fn st_of(&self, idx: Self::StEntryId) -> usize {
+        idx.st_idx
+    }
 }
 
 pub type Code = Vec<Item>;
@@ -28,27 +38,294 @@ pub enum Item {
     Function { entry: StEntryRef, body: Body },
 }
 
-pub type Body = Vec<Statement>;
+pub type Body<Id = StEntryRef> = Vec<Statement<Id>>;
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-pub enum Statement {
+pub enum Statement<Id = StEntryRef> {
+    /// Performs a syscall, ignoring its result. Output of the library's printing functions is
+    /// buffered and bypassed by syscalls, so `flush` should be called before writing to stdout
+    /// or stderr directly to keep them in order
     Syscall {
-        syscall: Expression,
-        args: Vec<Expression>,
+        syscall: Syscall<Id>,
+        args: Vec<Expression<Id>>,
+    },
+    /// Performs a syscall, assigning its result to `result`, a 64 bit integer or pointer, when it
+    /// succeeds, or running `on_error` when it fails
+    TrySyscall {
+        syscall: Syscall<Id>,
+        args: Vec<Expression<Id>>,
+        result: Option<String>,
+        on_error: ErrorArm<Id>,
     },
+    /// Returning the result of a call is compiled into a tail call, which reuses the stack frame
     Return {
-        inner: Option<Expression>,
+        inner: Option<Expression<Id>>,
+    },
+    /// Evaluates an expression (usually a call) and discards its value
+    Expression {
+        expr: Expression<Id>,
+    },
+    /// A nested scope declaring the variables of the `st`th symbol table
+    Block {
+        st: usize,
+        body: Body<Id>,
+    },
+    Assign {
+        name: String,
+        val: Expression<Id>,
+    },
+    /// Writes `val` to the memory at `addr`, copying the whole of aggregates
+    Store {
+        addr: Expression<Id>,
+        val: Expression<Id>,
+    },
+    /// A point `Goto`s in the same function can jump to
+    Label {
+        name: String,
+    },
+    /// Jumps to the label `name` of the current function, only if `cond` is non zero when given
+    Goto {
+        name: String,
+        cond: Option<Expression<Id>>,
+    },
+    /// Runs the arm for the variant of the enum value `scrutinee`, or `default` if none matches
+    Match {
+        scrutinee: Expression<Id>,
+        arms: Vec<MatchArm<Id>>,
+        default: Option<Body<Id>>,
+    },
+    /// Writes `message`, the current function and `location` to stderr, then terminates the
+    /// process
+    Panic {
+        message: String,
+        location: Option<Location>,
+    },
+}
+
+/// A position in the source the JSON was generated from
+#[derive(Debug, serde::Deserialize)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A Linux x86-64 syscall, by name (like `"write"`) or by number. The arguments of named ones,
+/// and of constant numbers of known ones, are checked against its signature
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Syscall<Id = StEntryRef> {
+    Name(String),
+    Number(Box<Expression<Id>>),
+}
+
+/// The block run by [`Statement::TrySyscall`] when the syscall fails
+#[derive(Debug, serde::Deserialize)]
+pub struct ErrorArm<Id = StEntryRef> {
+    /// Symbol table of the scope of the block, declaring `errno`
+    pub st: usize,
+    /// Variable the (positive) error code is copied into
+    pub errno: Option<String>,
+    pub body: Body<Id>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MatchArm<Id = StEntryRef> {
+    pub variant: String,
+    /// Symbol table of the scope of the arm, declaring the variables in `bindings`
+    pub st: usize,
+    /// Variables the fields of the variant are copied into, in order, skipping `null`s
+    #[serde(default)]
+    pub bindings: Vec<Option<String>>,
+    pub body: Body<Id>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Expression<Id = StEntryRef> {
+    IntConst {
+        val: u64,
+    },
+    /// Address of a NUL terminated copy of `val`
+    StrConst {
+        val: String,
+    },
+    /// Length in bytes of `val`, without the NUL terminator of [`Expression::StrConst`]
+    StrLen {
+        val: String,
+    },
+    FloatConst {
+        val: f64,
+        #[serde(default = "default_float_type")]
+        ty: Type,
+    },
+    /// The `idx`th argument of the current function
+    Arg {
+        idx: usize,
+    },
+    /// A variable, resolved from the innermost block outwards to the function and global scopes
+    Var {
+        name: String,
+    },
+    BinOp {
+        op: BinOp,
+        lhs: Box<Expression<Id>>,
+        rhs: Box<Expression<Id>>,
+    },
+    /// Compares both operands, evaluating to 1 if the comparison holds and 0 otherwise
+    Cmp {
+        op: CmpOp,
+        lhs: Box<Expression<Id>>,
+        rhs: Box<Expression<Id>>,
+    },
+    /// Numeric conversion between integers and floats, or between float sizes
+    Convert {
+        expr: Box<Expression<Id>>,
+        to: Type,
+    },
+    /// Conversion between integer and pointer types, truncating and then sign or zero
+    /// extending as `to` requires
+    Cast {
+        expr: Box<Expression<Id>>,
+        to: Type,
+    },
+    Call {
+        func: Id,
+        args: Vec<Expression<Id>>,
+    },
+    /// Address of a function, to be called through [`Expression::CallIndirect`]
+    FnAddr {
+        func: Id,
+    },
+    /// Builds a value of the enum `ty` with the given variant
+    Construct {
+        ty: String,
+        variant: String,
+        fields: Vec<Expression<Id>>,
+    },
+    /// Calls the function at `addr`, whose signature is given by the types of `args` and `ret`
+    CallIndirect {
+        addr: Box<Expression<Id>>,
+        args: Vec<Expression<Id>>,
+        ret: Type,
+        #[serde(default)]
+        variadic: bool,
+    },
+    /// Builds a tuple out of the values of `elems`
+    Tuple {
+        elems: Vec<Expression<Id>>,
+    },
+    /// The `idx`th element of a tuple
+    Field {
+        expr: Box<Expression<Id>>,
+        idx: usize,
+    },
+    /// Address of a blob laid out from `items`, in the read only data segment or, if `mutable`,
+    /// in a writable one. It's built once, so every evaluation yields the same address
+    Data {
+        items: Vec<DataItem<Id>>,
+        #[serde(default)]
+        mutable: bool,
+    },
+    /// Reads a `ty` from `addr`. Aggregates aren't copied, `addr` is used as their address
+    Load {
+        addr: Box<Expression<Id>>,
+        ty: Type,
+    },
+    /// A `va_list` positioned at the first variadic argument of the current function
+    VaStart,
+    /// Reads the next variadic argument, of type `ty`, advancing `list` (a `va_list` or a
+    /// pointer to one)
+    VaArg {
+        list: Box<Expression<Id>>,
+        ty: Type,
+    },
+    /// Finishes using `list`, evaluating to 0
+    VaEnd {
+        list: Box<Expression<Id>>,
+    },
+    /// The value of the entry `key` of the auxiliary vector passed by the kernel, such as
+    /// `AT_PAGESZ` (6), `AT_RANDOM` (25) or `AT_SYSINFO_EHDR` (33), or 0 if it's missing
+    Auxv {
+        key: Box<Expression<Id>>,
+    },
+    /// `clock_gettime(clock, ts)`, through the vDSO when the kernel maps one, evaluating to 0 or
+    /// `-errno`
+    ClockGettime {
+        clock: Box<Expression<Id>>,
+        ts: Box<Expression<Id>>,
+    },
+    /// Performs a syscall, evaluating to a tuple of its result and 0 when it succeeds, or of -1
+    /// and its (positive) error code when it fails
+    TrySyscall {
+        syscall: Syscall<Id>,
+        args: Vec<Expression<Id>>,
+    },
+    /// The Linux error number `name`, such as `EINTR` or `ENOENT`
+    Errno {
+        name: String,
     },
 }
 
+/// An entry of an [`Expression::Data`] blob, aligned to its size (8 bytes for addresses)
 #[derive(Debug, serde::Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-pub enum Expression {
-    IntConst { val: u64 },
-    StrConst { val: String },
+pub enum DataItem<Id = StEntryRef> {
+    Bytes {
+        val: Vec<u8>,
+    },
+    /// The bytes of `val`, without a NUL terminator
+    Str {
+        val: String,
+    },
+    /// The low bytes of `val` making up an integer of type `ty`
+    Int {
+        val: u64,
+        ty: Type,
+    },
+    Float {
+        val: f64,
+        ty: Type,
+    },
+    /// Address of a function
+    FnAddr {
+        func: Id,
+    },
+    /// Address of another blob
+    Data {
+        items: Vec<DataItem<Id>>,
+        #[serde(default)]
+        mutable: bool,
+    },
+}
+
+const fn default_float_type() -> Type {
+    Type::F64
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, serde::Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
 }
 
 pub fn run<S: St<StEntryId = StEntryRef>, C: Codegen<S>>(code: Code, mut codegen: C) {
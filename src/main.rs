@@ -2,7 +2,7 @@ use std::{fs::File, io::Write};
 
 use codegen::x64_linux_elf::{
     elf::{Elf64EHdr, ElfFileBuilder},
-    ir_gen::X64LinuxElfCodegen,
+    ir_gen::{Entry, Options, X64LinuxElfCodegen},
 };
 // use codegen::{LiteralAllocator, RegAllocator, codegen_body, codegen_code};
 use json::{run, Code, JsonSt};
@@ -15,7 +15,28 @@ fn main() {
     let code: Code =
         serde_json::from_str(include_str!("../examples/helloworld/code.json")).unwrap();
     let st: JsonSt = serde_json::from_str(include_str!("../examples/helloworld/st.json")).unwrap();
-    let codegen = X64LinuxElfCodegen::new(st);
+    let entry = std::env::args()
+        .find_map(|arg| {
+            if arg == "--no-entry" {
+                return Some(Entry::None);
+            }
+            if let Some(name) = arg.strip_prefix("--entry=") {
+                return Some(Entry::Main(name.to_string()));
+            }
+            let name = arg.strip_prefix("--start=")?;
+            Some(Entry::Start(name.to_string()))
+        })
+        .unwrap_or_default();
+    let options = Options {
+        entry,
+        checked_arithmetic: std::env::args().any(|arg| arg == "--checked-arithmetic"),
+        stack_protector: std::env::args().any(|arg| arg == "--stack-protector"),
+        main_stack_size: std::env::args().find_map(|arg| {
+            let size = arg.strip_prefix("--main-stack-size=")?;
+            Some(size.parse().expect("Invalid main stack size"))
+        }),
+    };
+    let codegen = X64LinuxElfCodegen::with_options(st, options);
     run(code, codegen);
     // let mut lit = LiteralAllocator::new(0);
     // let mut regs = RegAllocator::new();